@@ -20,6 +20,7 @@ fn start_kernel(boot_info: &'static BootInfo) -> ! {
     // Initialize the kernel.
     rustos::init();
     rustos::memory::init(boot_info);
+    rustos::init_apic();
 
     // Spawn async task(s).
     let mut executor = task::Executor::new();
@@ -7,44 +7,44 @@ use core::{
     mem,
     ptr::{self, NonNull},
 };
+use x86_64::{
+    structures::paging::{Page, PageSize, PageTableFlags, Size4KiB},
+    VirtAddr,
+};
+
+const BLOCK_SIZES: [usize; super::SIZE_CLASSES] = [8, 16, 32, 64, 128, 256, 512, 1024, 2048];
 
-const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+/// Slab size is the larger of the minimum slab size and `SLAB_BLOCKS` blocks.
+const SLAB_MIN_SIZE: usize = 4096;
+const SLAB_BLOCKS: usize = 8;
+
+/// Kernel heap minimum growth size.
+const HEAP_GROW_SIZE: usize = 64 * 1024; // 64KiB
 
 type LockedAllocator = super::Locked<Allocator>;
 
 unsafe impl GlobalAlloc for LockedAllocator {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
         let mut inner = self.lock();
-        match Allocator::list_index(&layout) {
-            Some(index) => match inner.list_heads[index].take() {
-                Some(node) => {
-                    inner.list_heads[index] = node.next.take();
-                    node as *mut Node as *mut u8
-                }
-                None => {
-                    let block_size = BLOCK_SIZES[index];
-                    let block_align = block_size;
-                    let layout = Layout::from_size_align(block_size, block_align).unwrap();
-                    inner.fallback_alloc(layout)
-                }
-            },
+        let ptr = match Allocator::list_index(&layout) {
+            Some(index) => inner.slab_alloc(index),
             None => inner.fallback_alloc(layout),
+        };
+        if !ptr.is_null() {
+            inner.stats.alloc(layout.size());
+            if let Some(index) = Allocator::list_index(&layout) {
+                inner.stats.block_allocations[index] += 1;
+            }
         }
+        ptr
     }
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
         let mut inner = self.lock();
+        inner.stats.dealloc(layout.size());
         match Allocator::list_index(&layout) {
             Some(index) => {
-                let new_node = Node {
-                    next: inner.list_heads[index].take(),
-                };
-                // make sure the block is bigger than node to hold.
-                assert!(mem::size_of::<Node>() <= BLOCK_SIZES[index]);
-                assert!(mem::align_of::<Node>() <= BLOCK_SIZES[index]);
-                #[allow(clippy::cast_ptr_alignment)]
-                let new_node_ptr = ptr as *mut Node;
-                new_node_ptr.write(new_node);
-                inner.list_heads[index] = Some(&mut *new_node_ptr);
+                inner.stats.block_allocations[index] -= 1;
+                inner.slab_dealloc(index, ptr);
             }
             None => {
                 let ptr = NonNull::new(ptr).unwrap();
@@ -54,33 +54,274 @@ unsafe impl GlobalAlloc for LockedAllocator {
     }
 }
 
+/// Fixed-size block allocator backed by the per size class slabs.
+///
+/// Each slab is a `slab_size()` aligned chunk of the fallback heap, holding
+/// the `Slab` header followed by the blocks of a single size class, so that
+/// the block finds its slab by the alignment.  The full slabs are kept on
+/// their own lists, so that the allocation always takes the head of the
+/// partial slab list.  Slabs are released back to the fallback heap once
+/// empty, so that the memory is reusable by the other size classes, except
+/// for one empty slab kept in each size class to avoid the release and the
+/// allocation of the slab on every other call.
 pub(super) struct Allocator {
-    list_heads: [Option<&'static mut Node>; BLOCK_SIZES.len()],
+    slabs: [Option<&'static mut Slab>; BLOCK_SIZES.len()],
+    full_slabs: [Option<&'static mut Slab>; BLOCK_SIZES.len()],
+    empty_slabs: [usize; BLOCK_SIZES.len()],
     fallback_allocator: linked_list_allocator::Heap,
+    stats: super::Stats,
 }
 
 impl Allocator {
     pub(super) const fn new() -> Self {
         Self {
-            list_heads: [None; BLOCK_SIZES.len()],
+            slabs: [None; BLOCK_SIZES.len()],
+            full_slabs: [None; BLOCK_SIZES.len()],
+            empty_slabs: [0; BLOCK_SIZES.len()],
             fallback_allocator: linked_list_allocator::Heap::empty(),
+            stats: super::Stats::new(&BLOCK_SIZES),
         }
     }
     pub(super) unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
         self.fallback_allocator.init(heap_start, heap_size);
+        self.stats.init(heap_size);
+    }
+    pub(super) fn stats(&self) -> super::Stats {
+        let mut stats = self.stats;
+        stats.free = self.fallback_allocator.free();
+        // the full slabs have no free blocks.
+        for slab in self.slabs.iter() {
+            let mut current = slab.as_ref();
+            while let Some(slab) = current {
+                stats.free += slab.free_blocks() * BLOCK_SIZES[slab.index];
+                current = slab.next.as_ref();
+            }
+        }
+        stats
+    }
+    unsafe fn slab_alloc(&mut self, index: usize) -> *mut u8 {
+        let slab = match self.slabs[index].as_mut() {
+            Some(slab) => {
+                if slab.used == 0 {
+                    // the kept empty slab is in use again.
+                    self.empty_slabs[index] -= 1;
+                }
+                &mut **slab as *mut Slab
+            }
+            None => {
+                let slab = self.new_slab(index);
+                if slab.is_null() {
+                    return ptr::null_mut();
+                }
+                slab
+            }
+        };
+        let block = (*slab).alloc().expect("full slab in the partial list");
+        if (*slab).is_full() {
+            self.unlink(slab);
+            (*slab).full = true;
+            self.push(slab);
+        }
+        block
+    }
+    // gets a new slab from the fallback allocator.
+    unsafe fn new_slab(&mut self, index: usize) -> *mut Slab {
+        let slab_size = Self::slab_size(index);
+        let layout = Layout::from_size_align(slab_size, slab_size).unwrap();
+        let ptr = self.fallback_alloc(layout);
+        if ptr.is_null() {
+            return ptr::null_mut();
+        }
+        #[allow(clippy::cast_ptr_alignment)]
+        let slab = ptr as *mut Slab;
+        slab.write(Slab::new(index));
+        self.push(slab);
+        slab
+    }
+    unsafe fn slab_dealloc(&mut self, index: usize, ptr: *mut u8) {
+        let slab_size = Self::slab_size(index);
+        #[allow(clippy::cast_ptr_alignment)]
+        let slab = (ptr as usize & !(slab_size - 1)) as *mut Slab;
+        (*slab).dealloc(ptr);
+        if (*slab).full {
+            self.unlink(slab);
+            (*slab).full = false;
+            self.push(slab);
+        }
+        if (*slab).used > 0 {
+            return;
+        }
+        if self.empty_slabs[index] == 0 {
+            self.empty_slabs[index] += 1;
+            return;
+        }
+        // release the extra empty slab back to the fallback allocator.
+        self.unlink(slab);
+        let ptr = NonNull::new(slab as *mut u8).unwrap();
+        let layout = Layout::from_size_align(slab_size, slab_size).unwrap();
+        self.fallback_allocator.deallocate(ptr, layout);
+    }
+    // returns the head of the full or the partial slab list.
+    fn head(&mut self, index: usize, full: bool) -> &mut Option<&'static mut Slab> {
+        if full {
+            &mut self.full_slabs[index]
+        } else {
+            &mut self.slabs[index]
+        }
+    }
+    // pushes the `slab` to the front of its list.
+    unsafe fn push(&mut self, slab: *mut Slab) {
+        let head = self.head((*slab).index, (*slab).full);
+        let next = head.take();
+        (*slab).prev = ptr::null_mut();
+        (*slab).next = next.map(|next| {
+            next.prev = slab;
+            next
+        });
+        *head = Some(&mut *slab);
+    }
+    // removes the `slab` from its list.
+    unsafe fn unlink(&mut self, slab: *mut Slab) {
+        let next = (*slab).next.take().map(|s| s as *mut Slab);
+        let prev = (*slab).prev;
+        if let Some(next) = next {
+            (*next).prev = prev;
+        }
+        let link = if prev.is_null() {
+            self.head((*slab).index, (*slab).full)
+        } else {
+            &mut (*prev).next
+        };
+        *link = next.map(|s| &mut *s);
+    }
+    fn slab_size(index: usize) -> usize {
+        (BLOCK_SIZES[index] * SLAB_BLOCKS).max(SLAB_MIN_SIZE)
     }
     fn fallback_alloc(&mut self, layout: Layout) -> *mut u8 {
-        match self.fallback_allocator.allocate_first_fit(layout) {
-            Ok(ptr) => ptr.as_ptr(),
-            Err(_) => ptr::null_mut(),
+        loop {
+            match self.fallback_allocator.allocate_first_fit(layout) {
+                Ok(ptr) => return ptr.as_ptr(),
+                Err(_) => {
+                    if !self.grow(layout) {
+                        return ptr::null_mut();
+                    }
+                }
+            }
         }
     }
+    /// Grows the fallback heap enough to hold `layout`.
+    fn grow(&mut self, layout: Layout) -> bool {
+        let size = layout.size() + layout.align();
+        let grown = grow(self.fallback_allocator.top(), size);
+        if grown == 0 {
+            return false;
+        }
+        unsafe { self.fallback_allocator.extend(grown) };
+        self.stats.grow(grown);
+        true
+    }
     fn list_index(layout: &Layout) -> Option<usize> {
         let required_block_size = layout.size().max(layout.align());
         BLOCK_SIZES.iter().position(|&s| s >= required_block_size)
     }
 }
 
+/// Maps at least `size` bytes of the new heap pages right after `heap_end`,
+/// up to `super::HEAP_MAX_SIZE`.
+///
+/// Returns the number of bytes newly mapped, or zero when `size` doesn't fit
+/// under the ceiling or the frames run out, in which case nothing is left
+/// mapped.
+fn grow(heap_end: usize, size: usize) -> usize {
+    let page_size = Size4KiB::SIZE as usize;
+    let available = (super::heap_start() + super::HEAP_MAX_SIZE).saturating_sub(heap_end);
+    let size = super::align_up(size, page_size);
+    if size > available {
+        return 0;
+    }
+    let size = size.max(HEAP_GROW_SIZE).min(available);
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let page = |offset: usize| Page::containing_address(VirtAddr::new((heap_end + offset) as u64));
+    let mut mapped = 0;
+    while mapped < size {
+        if crate::memory::map_page(page(mapped), flags).is_err() {
+            // roll back the partial growth.
+            while mapped > 0 {
+                mapped -= page_size;
+                unsafe { crate::memory::unmap(page(mapped)) }.expect("heap unmap failed");
+            }
+            return 0;
+        }
+        mapped += page_size;
+    }
+    mapped
+}
+
+struct Slab {
+    next: Option<&'static mut Slab>,
+    // previous slab in the list, or null for the head.
+    prev: *mut Slab,
+    // true when it's on the full slab list.
+    full: bool,
+    free_list: Option<&'static mut Node>,
+    // offset of the first never used block.
+    unused: usize,
+    used: usize,
+    index: usize,
+}
+
+// The slabs are only reached through the allocator lock.
+unsafe impl Send for Slab {}
+
+impl Slab {
+    fn new(index: usize) -> Self {
+        Self {
+            next: None,
+            prev: ptr::null_mut(),
+            full: false,
+            free_list: None,
+            unused: super::align_up(mem::size_of::<Self>(), BLOCK_SIZES[index]),
+            used: 0,
+            index,
+        }
+    }
+    fn free_blocks(&self) -> usize {
+        let header = super::align_up(mem::size_of::<Self>(), BLOCK_SIZES[self.index]);
+        (Allocator::slab_size(self.index) - header) / BLOCK_SIZES[self.index] - self.used
+    }
+    fn is_full(&self) -> bool {
+        self.free_list.is_none()
+            && self.unused + BLOCK_SIZES[self.index] > Allocator::slab_size(self.index)
+    }
+    unsafe fn alloc(&mut self) -> Option<*mut u8> {
+        if let Some(node) = self.free_list.take() {
+            self.free_list = node.next.take();
+            self.used += 1;
+            return Some(node as *mut Node as *mut u8);
+        }
+        let block_size = BLOCK_SIZES[self.index];
+        if self.unused + block_size > Allocator::slab_size(self.index) {
+            return None;
+        }
+        let block = self as *mut Self as usize + self.unused;
+        self.unused += block_size;
+        self.used += 1;
+        Some(block as *mut u8)
+    }
+    unsafe fn dealloc(&mut self, ptr: *mut u8) {
+        // make sure the block is bigger than node to hold.
+        assert!(mem::size_of::<Node>() <= BLOCK_SIZES[self.index]);
+        assert!(mem::align_of::<Node>() <= BLOCK_SIZES[self.index]);
+        #[allow(clippy::cast_ptr_alignment)]
+        let node_ptr = ptr as *mut Node;
+        node_ptr.write(Node {
+            next: self.free_list.take(),
+        });
+        self.free_list = Some(&mut *node_ptr);
+        self.used -= 1;
+    }
+}
+
 struct Node {
     next: Option<&'static mut Node>,
 }
@@ -18,14 +18,14 @@ unsafe impl GlobalAlloc for LockedAllocator {
             ptr::null_mut()
         } else {
             inner.next = alloc_end;
-            inner.allocations += 1;
+            inner.stats.alloc(layout.size());
             alloc_start as *mut u8
         }
     }
-    unsafe fn dealloc(&self, _ptr: *mut u8, _layout: Layout) {
+    unsafe fn dealloc(&self, _ptr: *mut u8, layout: Layout) {
         let mut inner = self.lock();
-        inner.allocations -= 1;
-        if inner.allocations == 0 {
+        inner.stats.dealloc(layout.size());
+        if inner.stats.allocations == 0 {
             inner.next = inner.heap_start;
         }
     }
@@ -35,23 +35,27 @@ pub(super) struct Allocator {
     heap_start: usize,
     heap_end: usize,
     next: usize,
-    allocations: usize,
+    stats: super::Stats,
 }
 
 impl Allocator {
-    #[allow(dead_code)]
     pub(super) const fn new() -> Self {
         Self {
             heap_start: 0,
             heap_end: 0,
             next: 0,
-            allocations: 0,
+            stats: super::Stats::new(&[]),
         }
     }
-    #[allow(dead_code)]
     pub(super) unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
         self.heap_start = heap_start;
         self.heap_end = heap_start + heap_size;
         self.next = heap_start;
+        self.stats.init(heap_size);
+    }
+    pub(super) fn stats(&self) -> super::Stats {
+        let mut stats = self.stats;
+        stats.free = self.heap_end - self.next;
+        stats
     }
 }
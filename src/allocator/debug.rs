@@ -0,0 +1,171 @@
+//! Debug allocator wrapper
+//!
+//! It surrounds each allocation with the guard bytes, poisons the freed
+//! memory, and panics on the double free, the layout mismatch and the
+//! corrupted guard bytes.  The freed blocks stay in the quarantine for a
+//! while before going back to the wrapped allocator, so that the freed
+//! header and the poison survive the following allocations.
+use super::alloc::alloc::{GlobalAlloc, Layout};
+use core::{
+    mem,
+    ops::Deref,
+    ptr, slice,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use spin::Mutex;
+
+const GUARD_SIZE: usize = 16;
+const GUARD_BYTE: u8 = 0xfd;
+const ALLOC_BYTE: u8 = 0xcd;
+const POISON_BYTE: u8 = 0x6b;
+const ALLOCATED: u64 = 0x_a110_ca7e_d0d0_a110;
+const FREED: u64 = 0x_f7ee_d0d0_f7ee_d0d0;
+// Room for the wrapped allocator's free list node, so that freeing the
+// block doesn't clobber the header.
+const NODE_SIZE: usize = 16;
+// Number of the freed blocks held back from the wrapped allocator.
+const QUARANTINE_SIZE: usize = 64;
+
+/// Debug allocator wrapping another global allocator.
+///
+/// It keeps the caller side statistics, as the wrapped allocator only sees
+/// the padded layouts and the quarantined blocks.
+pub(super) struct Allocator<A> {
+    inner: A,
+    quarantine: Mutex<Quarantine>,
+    allocated: AtomicUsize,
+    allocations: AtomicUsize,
+    peak: AtomicUsize,
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for Allocator<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let (outer, prefix) = Self::outer_layout(layout);
+        let base = self.inner.alloc(outer);
+        if base.is_null() {
+            return base;
+        }
+        let ptr = base.add(prefix);
+        Self::header(ptr).write(Header {
+            magic: ALLOCATED,
+            size: layout.size(),
+            align: layout.align(),
+        });
+        ptr::write_bytes(ptr.sub(GUARD_SIZE), GUARD_BYTE, GUARD_SIZE);
+        ptr::write_bytes(ptr, ALLOC_BYTE, layout.size());
+        ptr::write_bytes(ptr.add(layout.size()), GUARD_BYTE, GUARD_SIZE);
+        let allocated = self.allocated.fetch_add(layout.size(), Ordering::SeqCst) + layout.size();
+        let mut peak = self.peak.load(Ordering::SeqCst);
+        while peak < allocated {
+            match self
+                .peak
+                .compare_exchange(peak, allocated, Ordering::SeqCst, Ordering::SeqCst)
+            {
+                Ok(_) => break,
+                Err(current) => peak = current,
+            }
+        }
+        self.allocations.fetch_add(1, Ordering::SeqCst);
+        ptr
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let header = &mut *Self::header(ptr);
+        match header.magic {
+            ALLOCATED => {}
+            FREED => panic!("double free of {:p} with {:?}", ptr, layout),
+            _ => panic!("free of unknown pointer {:p} with {:?}", ptr, layout),
+        }
+        if header.size != layout.size() || header.align != layout.align() {
+            panic!(
+                "layout mismatch on free of {:p}: allocated with size {} align {}, freed with {:?}",
+                ptr, header.size, header.align, layout,
+            );
+        }
+        let front = slice::from_raw_parts(ptr.sub(GUARD_SIZE), GUARD_SIZE);
+        let back = slice::from_raw_parts(ptr.add(layout.size()), GUARD_SIZE);
+        if front.iter().chain(back.iter()).any(|&b| b != GUARD_BYTE) {
+            panic!("red zone overwritten around {:p} with {:?}", ptr, layout);
+        }
+        header.magic = FREED;
+        ptr::write_bytes(ptr, POISON_BYTE, layout.size());
+        self.allocated.fetch_sub(layout.size(), Ordering::SeqCst);
+        self.allocations.fetch_sub(1, Ordering::SeqCst);
+        let evicted = self.quarantine.lock().push(ptr, layout);
+        if let Some((ptr, layout)) = evicted {
+            let block = slice::from_raw_parts(ptr, layout.size());
+            if block.iter().any(|&b| b != POISON_BYTE) {
+                panic!("use after free of {:p} with {:?}", ptr, layout);
+            }
+            let (outer, prefix) = Self::outer_layout(layout);
+            self.inner.dealloc(ptr.sub(prefix), outer);
+        }
+    }
+}
+
+impl<A> Deref for Allocator<A> {
+    type Target = A;
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
+impl<A> Allocator<A> {
+    pub(super) const fn new(inner: A) -> Self {
+        Self {
+            inner,
+            quarantine: Mutex::new(Quarantine::new()),
+            allocated: AtomicUsize::new(0),
+            allocations: AtomicUsize::new(0),
+            peak: AtomicUsize::new(0),
+        }
+    }
+    /// Replaces the wrapped allocator's statistics with the caller side
+    /// ones.  The size class counts still reflect the padded layouts.
+    pub(super) fn caller_stats(&self, mut stats: super::Stats) -> super::Stats {
+        stats.allocated = self.allocated.load(Ordering::SeqCst);
+        stats.allocations = self.allocations.load(Ordering::SeqCst);
+        stats.peak = self.peak.load(Ordering::SeqCst);
+        stats
+    }
+    /// Returns the wrapped allocation layout and the offset to the
+    /// caller's memory.
+    fn outer_layout(layout: Layout) -> (Layout, usize) {
+        let align = layout.align().max(mem::align_of::<Header>());
+        let prefix = super::align_up(NODE_SIZE + mem::size_of::<Header>() + GUARD_SIZE, align);
+        let size = prefix + layout.size() + GUARD_SIZE;
+        (Layout::from_size_align(size, align).unwrap(), prefix)
+    }
+    fn header(ptr: *mut u8) -> *mut Header {
+        #[allow(clippy::cast_ptr_alignment)]
+        unsafe {
+            ptr.sub(GUARD_SIZE + mem::size_of::<Header>()) as *mut Header
+        }
+    }
+}
+
+struct Header {
+    magic: u64,
+    size: usize,
+    align: usize,
+}
+
+/// Ring of the recently freed blocks, with the caller's layouts.
+struct Quarantine {
+    blocks: [Option<(usize, Layout)>; QUARANTINE_SIZE],
+    next: usize,
+}
+
+impl Quarantine {
+    const fn new() -> Self {
+        Self {
+            blocks: [None; QUARANTINE_SIZE],
+            next: 0,
+        }
+    }
+    // holds the freed block, and returns the oldest one once full.
+    fn push(&mut self, ptr: *mut u8, layout: Layout) -> Option<(*mut u8, Layout)> {
+        let evicted = self.blocks[self.next].replace((ptr as usize, layout));
+        self.next = (self.next + 1) % QUARANTINE_SIZE;
+        evicted.map(|(ptr, layout)| (ptr as *mut u8, layout))
+    }
+}
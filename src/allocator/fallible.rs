@@ -0,0 +1,146 @@
+//! Fallible allocation API
+//!
+//! Those return the error to the caller instead of calling the allocation
+//! error handler, after asking the registered OOM handlers to shrink their
+//! subsystems.  The infallible allocations ask those handlers, too, before
+//! giving up.
+use super::alloc::{
+    alloc::{alloc, GlobalAlloc, Layout},
+    boxed::Box,
+    vec::Vec,
+};
+use core::{fmt, mem, ops::Deref, ptr::NonNull};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+const MAX_OOM_HANDLERS: usize = 8;
+
+static OOM_HANDLERS: Mutex<[Option<OomHandler>; MAX_OOM_HANDLERS]> =
+    Mutex::new([None; MAX_OOM_HANDLERS]);
+
+/// OOM handler, which shrinks its subsystem and returns the released bytes.
+pub type OomHandler = fn() -> usize;
+
+/// Fallible allocation error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AllocError {
+    /// The requested capacity exceeds the maximum allocation size.
+    CapacityOverflow,
+    /// The allocator couldn't satisfy the layout.
+    OutOfMemory(Layout),
+}
+
+impl fmt::Display for AllocError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::CapacityOverflow => write!(f, "capacity overflow"),
+            Self::OutOfMemory(layout) => write!(f, "out of memory: {:?}", layout),
+        }
+    }
+}
+
+/// Registers the OOM handler, which will be called before the fallible
+/// allocation gives up.
+///
+/// It returns the handler back when there is no room for it.
+pub fn register_oom_handler(handler: OomHandler) -> Result<(), OomHandler> {
+    interrupts::without_interrupts(|| {
+        let mut handlers = OOM_HANDLERS.lock();
+        match handlers.iter_mut().find(|h| h.is_none()) {
+            Some(slot) => {
+                *slot = Some(handler);
+                Ok(())
+            }
+            None => Err(handler),
+        }
+    })
+}
+
+/// Asks all the registered OOM handlers to shrink and returns the total
+/// released bytes.
+pub fn reclaim() -> usize {
+    // Copy the handlers out, so that those can allocate or register.
+    let handlers = interrupts::without_interrupts(|| *OOM_HANDLERS.lock());
+    handlers.iter().flatten().map(|handler| handler()).sum()
+}
+
+/// Allocates the memory for `layout`, which the global allocator retries
+/// once after `reclaim`.
+///
+/// It returns the dangling pointer for the zero sized `layout`.
+pub fn try_alloc(layout: Layout) -> Result<NonNull<u8>, AllocError> {
+    if layout.size() == 0 {
+        return Ok(layout.dangling());
+    }
+    NonNull::new(unsafe { alloc(layout) }).ok_or(AllocError::OutOfMemory(layout))
+}
+
+/// Global allocator wrapper, which retries the failed allocation once after
+/// `reclaim`, so that the OOM handlers run before the allocation error
+/// handler, too.
+pub(super) struct Reclaiming<A> {
+    inner: A,
+}
+
+impl<A> Reclaiming<A> {
+    pub(super) const fn new(inner: A) -> Self {
+        Self { inner }
+    }
+}
+
+impl<A> Deref for Reclaiming<A> {
+    type Target = A;
+    fn deref(&self) -> &A {
+        &self.inner
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for Reclaiming<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.inner.alloc(layout);
+        if ptr.is_null() && reclaim() > 0 {
+            return self.inner.alloc(layout);
+        }
+        ptr
+    }
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.inner.alloc_zeroed(layout);
+        if ptr.is_null() && reclaim() > 0 {
+            return self.inner.alloc_zeroed(layout);
+        }
+        ptr
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.inner.dealloc(ptr, layout)
+    }
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_ptr = self.inner.realloc(ptr, layout, new_size);
+        if new_ptr.is_null() && reclaim() > 0 {
+            return self.inner.realloc(ptr, layout, new_size);
+        }
+        new_ptr
+    }
+}
+
+/// Fallible version of `Box::new`.
+pub fn try_box<T>(value: T) -> Result<Box<T>, AllocError> {
+    let layout = Layout::new::<T>();
+    if layout.size() == 0 {
+        return Ok(Box::new(value));
+    }
+    let ptr = try_alloc(layout)?.cast::<T>().as_ptr();
+    unsafe {
+        ptr.write(value);
+        Ok(Box::from_raw(ptr))
+    }
+}
+
+/// Fallible version of `Vec::with_capacity`.
+pub fn try_vec_with_capacity<T>(capacity: usize) -> Result<Vec<T>, AllocError> {
+    if capacity == 0 || mem::size_of::<T>() == 0 {
+        return Ok(Vec::with_capacity(capacity));
+    }
+    let layout = Layout::array::<T>(capacity).map_err(|_| AllocError::CapacityOverflow)?;
+    let ptr = try_alloc(layout)?.cast::<T>().as_ptr();
+    Ok(unsafe { Vec::from_raw_parts(ptr, 0, capacity) })
+}
@@ -16,6 +16,7 @@ unsafe impl GlobalAlloc for LockedAllocator {
             if excess_size > 0 {
                 inner.add_free_region(alloc_end, excess_size);
             }
+            inner.stats.alloc(layout.size());
             alloc_start as *mut u8
         } else {
             ptr::null_mut()
@@ -23,31 +24,75 @@ unsafe impl GlobalAlloc for LockedAllocator {
     }
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
         let (size, _) = Allocator::size_align(layout);
-        self.lock().add_free_region(ptr as usize, size)
+        let mut inner = self.lock();
+        inner.add_free_region(ptr as usize, size);
+        inner.stats.dealloc(layout.size());
     }
 }
 
 pub(super) struct Allocator {
     head: Node,
+    stats: super::Stats,
 }
 
 impl Allocator {
-    #[allow(dead_code)]
     pub(super) const fn new() -> Self {
-        Self { head: Node::new(0) }
+        Self {
+            head: Node::new(0),
+            stats: super::Stats::new(&[]),
+        }
     }
-    #[allow(dead_code)]
     pub(super) unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
         self.add_free_region(heap_start, heap_size);
+        self.stats.init(heap_size);
+    }
+    pub(super) fn stats(&self) -> super::Stats {
+        let mut stats = self.stats;
+        let mut current = &self.head;
+        while let Some(region) = current.next.as_ref() {
+            stats.free += region.size;
+            current = &**region;
+        }
+        stats
     }
+    /// Adds the free region in the address order, merging it with the
+    /// adjacent free regions.
     unsafe fn add_free_region(&mut self, addr: usize, size: usize) {
         assert!(super::align_up(addr, mem::align_of::<Node>()) == addr);
         assert!(size >= mem::size_of::<Node>());
+        // find the last region which starts before the freed one.
+        let head: *mut Node = &mut self.head;
+        let mut prev = head;
+        while let Some(next) = (*prev).next.as_mut() {
+            if next.start_addr() >= addr {
+                break;
+            }
+            prev = &mut **next;
+        }
         let mut node = Node::new(size);
-        node.next = self.head.next.take();
-        let node_ptr = addr as *mut Node;
-        node_ptr.write(node);
-        self.head.next = Some(&mut *node_ptr)
+        node.next = (*prev).next.take();
+        // merge with the next region.
+        if let Some(next) = node.next.take() {
+            assert!(addr + size <= next.start_addr(), "overlapping free region");
+            if addr + size == next.start_addr() {
+                node.size += next.size;
+                node.next = next.next.take();
+            } else {
+                node.next = Some(next);
+            }
+        }
+        // merge with the previous region.
+        if prev != head {
+            assert!((*prev).end_addr() <= addr, "overlapping free region");
+        }
+        if prev != head && (*prev).end_addr() == addr {
+            (*prev).size += node.size;
+            (*prev).next = node.next.take();
+        } else {
+            let node_ptr = addr as *mut Node;
+            node_ptr.write(node);
+            (*prev).next = Some(&mut *node_ptr)
+        }
     }
     fn find_region(&mut self, size: usize, align: usize) -> Option<(&'static mut Node, usize)> {
         let mut current = &mut self.head;
@@ -1,18 +1,37 @@
 //! Heap allocators
 extern crate alloc;
 use alloc::alloc::Layout;
+use core::sync::atomic::{AtomicUsize, Ordering};
 use spin::{Mutex, MutexGuard};
 use x86_64::{
+    instructions::interrupts,
     structures::paging::{
         mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB,
     },
     VirtAddr,
 };
 
-/// Kernel heap start address.
+/// Kernel heap start address, unless randomized by the `kaslr` feature.
 pub const HEAP_START: usize = 0x_4444_4444_0000;
 /// Kernel heap size.
 pub const HEAP_SIZE: usize = 100 * 1024; // 100KiB
+/// Kernel heap size ceiling, up to which the block allocator grows the heap
+/// on demand.
+pub const HEAP_MAX_SIZE: usize = 16 * 1024 * 1024; // 16MiB
+
+mod fallible;
+mod stats;
+
+static HEAP_BASE: AtomicUsize = AtomicUsize::new(HEAP_START);
+
+/// Re-exports.
+pub use fallible::{
+    reclaim, register_oom_handler, try_alloc, try_box, try_vec_with_capacity, AllocError,
+    OomHandler,
+};
+pub use stats::{Stats, SIZE_CLASSES};
+
+use fallible::Reclaiming;
 
 struct Locked<A> {
     inner: Mutex<A>,
@@ -29,14 +48,67 @@ impl<A> Locked<A> {
     }
 }
 
-/// Different allocator designs.
+/// Different allocator designs, selected by the cargo features.
+///
+/// The `alloc-bump` feature takes precedence over the `alloc-list` feature,
+/// which takes precedence over the default `alloc-block` feature.
+#[cfg(all(
+    feature = "alloc-block",
+    not(any(feature = "alloc-bump", feature = "alloc-list"))
+))]
 mod block;
+#[cfg(feature = "alloc-bump")]
 mod bump;
+#[cfg(all(feature = "alloc-list", not(feature = "alloc-bump")))]
 mod list;
 
+#[cfg(all(
+    feature = "alloc-block",
+    not(any(feature = "alloc-bump", feature = "alloc-list"))
+))]
+use block::Allocator;
+#[cfg(feature = "alloc-bump")]
+use bump::Allocator;
+#[cfg(all(feature = "alloc-list", not(feature = "alloc-bump")))]
+use list::Allocator;
+
+#[cfg(not(any(
+    feature = "alloc-block",
+    feature = "alloc-bump",
+    feature = "alloc-list"
+)))]
+compile_error!("one of alloc-block, alloc-bump or alloc-list feature is required");
+
+/// Debug allocator wrapper, enabled by the `alloc-debug` feature.
+#[cfg(feature = "alloc-debug")]
+mod debug;
+
+#[cfg(not(feature = "alloc-debug"))]
+#[global_allocator]
+static ALLOCATOR: Reclaiming<Locked<Allocator>> = Reclaiming::new(Locked::new(Allocator::new()));
+
+#[cfg(feature = "alloc-debug")]
 #[global_allocator]
-static ALLOCATOR: Locked<block::Allocator> = Locked::new(block::Allocator::new());
+static ALLOCATOR: Reclaiming<debug::Allocator<Locked<Allocator>>> =
+    Reclaiming::new(debug::Allocator::new(Locked::new(Allocator::new())));
+
+/// Returns the kernel heap start address.
+pub fn heap_start() -> usize {
+    HEAP_BASE.load(Ordering::Relaxed)
+}
+
+/// Returns the current heap allocator statistics.
+pub fn stats() -> Stats {
+    let stats = interrupts::without_interrupts(|| ALLOCATOR.lock().stats());
+    #[cfg(feature = "alloc-debug")]
+    let stats = ALLOCATOR.caller_stats(stats);
+    stats
+}
 
+/// Infallible allocation error handler, once the OOM handlers couldn't help.
+///
+/// Use the fallible allocation API, e.g. `try_box`, to handle the
+/// allocation errors gracefully.
 #[alloc_error_handler]
 fn alloc_error_handler(layout: Layout) -> ! {
     panic!("allocation error: {:?}", layout);
@@ -45,9 +117,10 @@ fn alloc_error_handler(layout: Layout) -> ! {
 pub(crate) fn init(
     mapper: &mut impl Mapper<Size4KiB>,
     frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    heap_start: VirtAddr,
 ) -> Result<(), MapToError<Size4KiB>> {
+    HEAP_BASE.store(heap_start.as_u64() as usize, Ordering::Relaxed);
     let page_range = {
-        let heap_start = VirtAddr::new(HEAP_START as u64);
         let heap_end = heap_start + HEAP_SIZE - 1u64;
         let heap_start_page = Page::containing_address(heap_start);
         let heap_end_page = Page::containing_address(heap_end);
@@ -57,11 +130,13 @@ pub(crate) fn init(
         let frame = frame_allocator
             .allocate_frame()
             .ok_or(MapToError::FrameAllocationFailed)?;
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
         mapper.map_to(page, frame, flags, frame_allocator)?.flush();
     }
     unsafe {
-        ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
+        ALLOCATOR
+            .lock()
+            .init(heap_start.as_u64() as usize, HEAP_SIZE);
     }
     Ok(())
 }
@@ -0,0 +1,76 @@
+//! Heap allocator statistics
+use core::fmt;
+
+/// Maximum number of the block allocator size classes.
+pub const SIZE_CLASSES: usize = 9;
+
+/// Heap allocator statistics.
+#[derive(Debug, Clone, Copy)]
+pub struct Stats {
+    /// Mapped heap size in bytes.
+    pub heap_size: usize,
+    /// Allocated bytes.
+    pub allocated: usize,
+    /// Free heap bytes still available to the allocations.  It excludes the
+    /// allocator's own overhead, e.g. the block rounding, the slab headers
+    /// and the alignment padding, so it's not `heap_size` minus `allocated`.
+    pub free: usize,
+    /// Peak allocated bytes.
+    pub peak: usize,
+    /// Number of live allocations.
+    pub allocations: usize,
+    /// Block sizes of the size classes, empty other than the block allocator.
+    pub block_sizes: &'static [usize],
+    /// Number of live allocations of each size class in `block_sizes`.
+    pub block_allocations: [usize; SIZE_CLASSES],
+}
+
+impl Stats {
+    pub(super) const fn new(block_sizes: &'static [usize]) -> Self {
+        Self {
+            heap_size: 0,
+            allocated: 0,
+            free: 0,
+            peak: 0,
+            allocations: 0,
+            block_sizes,
+            block_allocations: [0; SIZE_CLASSES],
+        }
+    }
+    pub(super) fn init(&mut self, heap_size: usize) {
+        self.heap_size = heap_size;
+    }
+    pub(super) fn grow(&mut self, size: usize) {
+        self.heap_size += size;
+    }
+    pub(super) fn alloc(&mut self, size: usize) {
+        self.allocated += size;
+        self.allocations += 1;
+        if self.allocated > self.peak {
+            self.peak = self.allocated;
+        }
+    }
+    pub(super) fn dealloc(&mut self, size: usize) {
+        self.allocated -= size;
+        self.allocations -= 1;
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "heap: {} bytes, allocated: {} bytes, free: {} bytes, ",
+            self.heap_size, self.allocated, self.free,
+        )?;
+        write!(
+            f,
+            "peak: {} bytes, allocations: {}",
+            self.peak, self.allocations,
+        )?;
+        for (size, count) in self.block_sizes.iter().zip(self.block_allocations.iter()) {
+            write!(f, "\n  {:>4} bytes: {}", size, count)?;
+        }
+        Ok(())
+    }
+}
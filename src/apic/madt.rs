@@ -0,0 +1,141 @@
+//! ACPI Multiple APIC Description Table discovery
+use core::{mem, slice};
+use x86_64::PhysAddr;
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+const MADT_SIGNATURE: &[u8; 4] = b"APIC";
+const MAX_OVERRIDES: usize = 16;
+pub(super) const MAX_IO_APICS: usize = 8;
+
+/// Interrupt source override, which maps the ISA IRQ to the GSI.
+#[derive(Debug, Clone, Copy)]
+pub(super) struct Override {
+    pub(super) irq: u8,
+    pub(super) gsi: u32,
+    pub(super) active_low: bool,
+    pub(super) level_triggered: bool,
+}
+
+/// Interrupt controllers described by the MADT.
+#[derive(Debug)]
+pub(super) struct Madt {
+    pub(super) local_apic: PhysAddr,
+    /// Address and the GSI base of each I/O APIC.
+    pub(super) io_apics: [Option<(PhysAddr, u32)>; MAX_IO_APICS],
+    overrides: [Option<Override>; MAX_OVERRIDES],
+}
+
+impl Madt {
+    /// Finds the MADT through the RSDP in the BIOS memory.
+    pub(super) fn find() -> Option<Self> {
+        let rsdp = find_rsdp()?;
+        let revision = read::<u8>(rsdp + 15u64);
+        let (root, entry_size) = if revision >= 2 {
+            (PhysAddr::new(read::<u64>(rsdp + 24u64)), 8)
+        } else {
+            (PhysAddr::new(u64::from(read::<u32>(rsdp + 16u64))), 4)
+        };
+        let length = u64::from(read::<u32>(root + 4u64));
+        let entries = (length - 36) / entry_size;
+        (0..entries)
+            .map(|i| {
+                let entry = root + 36u64 + i * entry_size;
+                match entry_size {
+                    8 => PhysAddr::new(read::<u64>(entry)),
+                    _ => PhysAddr::new(u64::from(read::<u32>(entry))),
+                }
+            })
+            .find(|&table| bytes(table, 4) == MADT_SIGNATURE && checksum(table))
+            .map(Self::parse)
+    }
+    /// Returns the GSI and the trigger mode of the ISA `irq`.
+    pub(super) fn isa_irq(&self, irq: u8) -> Override {
+        self.overrides
+            .iter()
+            .flatten()
+            .find(|o| o.irq == irq)
+            .copied()
+            .unwrap_or(Override {
+                irq,
+                gsi: u32::from(irq),
+                active_low: false,
+                level_triggered: false,
+            })
+    }
+    fn parse(table: PhysAddr) -> Self {
+        let length = u64::from(read::<u32>(table + 4u64));
+        let mut madt = Self {
+            local_apic: PhysAddr::new(u64::from(read::<u32>(table + 36u64))),
+            io_apics: [None; MAX_IO_APICS],
+            overrides: [None; MAX_OVERRIDES],
+        };
+        let mut offset = 44;
+        while offset + 2 <= length {
+            let entry = table + offset;
+            let (kind, len) = (read::<u8>(entry), u64::from(read::<u8>(entry + 1u64)));
+            if len < 2 {
+                break;
+            }
+            match kind {
+                // I/O APIC.
+                1 => {
+                    let addr = PhysAddr::new(u64::from(read::<u32>(entry + 4u64)));
+                    let slot = madt.io_apics.iter_mut().find(|a| a.is_none());
+                    if let Some(slot) = slot {
+                        *slot = Some((addr, read::<u32>(entry + 8u64)));
+                    }
+                }
+                // Interrupt source override.
+                2 => {
+                    let flags = read::<u16>(entry + 8u64);
+                    let slot = madt.overrides.iter_mut().find(|o| o.is_none());
+                    if let Some(slot) = slot {
+                        *slot = Some(Override {
+                            irq: read::<u8>(entry + 3u64),
+                            gsi: read::<u32>(entry + 4u64),
+                            active_low: flags & 0b11 == 0b11,
+                            level_triggered: (flags >> 2) & 0b11 == 0b11,
+                        });
+                    }
+                }
+                // Local APIC address override.
+                5 => madt.local_apic = PhysAddr::new(read::<u64>(entry + 4u64)),
+                _ => {}
+            }
+            offset += len;
+        }
+        madt
+    }
+}
+
+// searches the first KiB of the EBDA and the BIOS ROM for the RSDP.
+fn find_rsdp() -> Option<PhysAddr> {
+    let ebda = u64::from(read::<u16>(PhysAddr::new(0x40e))) << 4;
+    let areas = [(ebda, ebda + 1024), (0xe_0000, 0x10_0000)];
+    areas.iter().find_map(|&(start, end)| {
+        (start..end)
+            .step_by(16)
+            .map(PhysAddr::new)
+            .find(|&addr| bytes(addr, 8) == RSDP_SIGNATURE && sum(bytes(addr, 20)) == 0)
+    })
+}
+
+fn checksum(table: PhysAddr) -> bool {
+    let length = read::<u32>(table + 4u64) as usize;
+    sum(bytes(table, length)) == 0
+}
+
+fn sum(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b))
+}
+
+fn bytes(addr: PhysAddr, len: usize) -> &'static [u8] {
+    let virt = crate::memory::physical_memory_offset() + addr.as_u64();
+    unsafe { slice::from_raw_parts(virt.as_ptr(), len) }
+}
+
+fn read<T: Copy>(addr: PhysAddr) -> T {
+    let virt = crate::memory::physical_memory_offset() + addr.as_u64();
+    debug_assert!(mem::size_of::<T>() <= 8);
+    unsafe { virt.as_ptr::<T>().read_unaligned() }
+}
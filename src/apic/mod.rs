@@ -0,0 +1,237 @@
+//! Local APIC and I/O APIC interrupt controllers
+//!
+//! Both are discovered through the ACPI MADT.  Once enabled, the 8259 PIC
+//! pair is masked, the ISA timer, keyboard and RTC IRQs are routed through
+//! the redirection table of the I/O APIC covering their GSI, and the
+//! handlers acknowledge the local APIC.
+mod madt;
+
+use self::madt::{Madt, Override, MAX_IO_APICS};
+use crate::interrupts::InterruptIndex;
+use crate::memory::{map_mmio, Mmio};
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use volatile::Volatile;
+use x86_64::{instructions::interrupts, registers::model_specific::Msr};
+
+/// Spurious interrupt vector, which needs no EOI.
+pub(crate) const SPURIOUS_VECTOR: u8 = 0xff;
+
+const IA32_APIC_BASE: u32 = 0x1b;
+const APIC_GLOBAL_ENABLE: u64 = 1 << 11;
+
+// local APIC register offsets.
+const LAPIC_ID: usize = 0x20;
+const LAPIC_VERSION: usize = 0x30;
+const LAPIC_EOI: usize = 0xb0;
+const LAPIC_SPURIOUS: usize = 0xf0;
+const LAPIC_SOFTWARE_ENABLE: u32 = 1 << 8;
+
+// I/O APIC register indexes.
+const IOAPIC_VERSION: u32 = 0x01;
+const IOAPIC_REDIRECTION: u32 = 0x10;
+const IOAPIC_ACTIVE_LOW: u32 = 1 << 13;
+const IOAPIC_LEVEL_TRIGGERED: u32 = 1 << 15;
+
+// ISA IRQ numbers.
+const TIMER_IRQ: u8 = 0;
+const KEYBOARD_IRQ: u8 = 1;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static LOCAL_APIC: Mutex<Option<LocalApic>> = Mutex::new(None);
+static IO_APICS: Mutex<[Option<IoApic>; MAX_IO_APICS]> = Mutex::new([None; MAX_IO_APICS]);
+
+/// APIC setup errors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ApicError {
+    /// No I/O APIC covers the GSI.
+    GsiNotCovered(u32),
+}
+
+/// Local APIC, 4KiB of the 32 bit registers on the 16 byte boundaries.
+struct LocalApic {
+    regs: Mmio<[Volatile<u32>; 1024]>,
+}
+
+impl LocalApic {
+    fn read(&self, reg: usize) -> u32 {
+        self.regs[reg / 4].read()
+    }
+    fn write(&mut self, reg: usize, value: u32) {
+        self.regs[reg / 4].write(value)
+    }
+    fn id(&self) -> u8 {
+        (self.read(LAPIC_ID) >> 24) as u8
+    }
+}
+
+/// I/O APIC, accessed through the `IOREGSEL` and `IOWIN` registers.
+struct IoApic {
+    regs: Mmio<[Volatile<u32>; 8]>,
+    gsi_base: u32,
+}
+
+impl IoApic {
+    fn read(&mut self, reg: u32) -> u32 {
+        self.regs[0].write(reg);
+        self.regs[4].read()
+    }
+    fn write(&mut self, reg: u32, value: u32) {
+        self.regs[0].write(reg);
+        self.regs[4].write(value)
+    }
+    fn redirections(&mut self) -> u32 {
+        ((self.read(IOAPIC_VERSION) >> 16) & 0xff) + 1
+    }
+    fn covers(&mut self, gsi: u32) -> bool {
+        gsi >= self.gsi_base && gsi - self.gsi_base < self.redirections()
+    }
+    // routes the ISA IRQ to `vector` on the local APIC `dest`.
+    fn route(&mut self, irq: Override, vector: u8, dest: u8) {
+        let entry = irq.gsi - self.gsi_base;
+        assert!(entry < self.redirections(), "gsi out of the i/o apic");
+        let mut low = u32::from(vector);
+        if irq.active_low {
+            low |= IOAPIC_ACTIVE_LOW;
+        }
+        if irq.level_triggered {
+            low |= IOAPIC_LEVEL_TRIGGERED;
+        }
+        self.write(IOAPIC_REDIRECTION + entry * 2 + 1, u32::from(dest) << 24);
+        self.write(IOAPIC_REDIRECTION + entry * 2, low);
+    }
+}
+
+/// Switches from the 8259 PIC to the APIC, when the MADT describes one.
+///
+/// It needs the remapped PIC to mask, and the memory manager for the
+/// register mappings, and keeps the PIC when anything is missing.
+pub(crate) fn init() {
+    assert!(
+        crate::interrupts::pics_initialized(),
+        "apic::init before interrupts::init"
+    );
+    let madt = match Madt::find() {
+        Some(madt) => madt,
+        None => {
+            crate::serial_println!("apic: no MADT, keep the 8259 PIC");
+            return;
+        }
+    };
+    if madt.io_apics[0].is_none() {
+        crate::serial_println!("apic: no I/O APIC, keep the 8259 PIC");
+        return;
+    }
+    let mut local_apic = LocalApic {
+        regs: unsafe { map_mmio(madt.local_apic, 4096) }.expect("local apic mapping failed"),
+    };
+    let mut io_apics: [Option<IoApic>; MAX_IO_APICS] = [None; MAX_IO_APICS];
+    for (slot, &(addr, gsi_base)) in io_apics.iter_mut().zip(madt.io_apics.iter().flatten()) {
+        slot.replace(IoApic {
+            regs: unsafe { map_mmio(addr, 32) }.expect("i/o apic mapping failed"),
+            gsi_base,
+        });
+    }
+    let routes: [(u8, u8); 3] = [
+        (TIMER_IRQ, InterruptIndex::Timer.as_u8()),
+        (KEYBOARD_IRQ, InterruptIndex::Keyboard.as_u8()),
+        (crate::rtc::RTC_IRQ, InterruptIndex::Rtc.as_u8()),
+    ];
+    // find all the I/O APICs before leaving the PIC.
+    let mut targets = [0; 3];
+    for (target, &(irq, _)) in targets.iter_mut().zip(routes.iter()) {
+        match find_io_apic(&mut io_apics, madt.isa_irq(irq).gsi) {
+            Ok(index) => *target = index,
+            Err(err) => {
+                crate::serial_println!("apic: {:?}, keep the 8259 PIC", err);
+                return;
+            }
+        }
+    }
+    interrupts::without_interrupts(|| {
+        crate::interrupts::disable_pics();
+        unsafe {
+            let mut msr = Msr::new(IA32_APIC_BASE);
+            msr.write(msr.read() | APIC_GLOBAL_ENABLE);
+        }
+        local_apic.write(
+            LAPIC_SPURIOUS,
+            LAPIC_SOFTWARE_ENABLE | u32::from(SPURIOUS_VECTOR),
+        );
+        let dest = local_apic.id();
+        for (&(irq, vector), &index) in routes.iter().zip(targets.iter()) {
+            let io_apic = io_apics[index].as_mut().unwrap();
+            io_apic.route(madt.isa_irq(irq), vector, dest);
+        }
+        LOCAL_APIC.lock().replace(local_apic);
+        *IO_APICS.lock() = io_apics;
+        ENABLED.store(true, Ordering::SeqCst);
+    });
+    crate::serial_println!(
+        "apic: local apic at {:#x}, {} i/o apic(s)",
+        madt.local_apic.as_u64(),
+        madt.io_apics.iter().flatten().count(),
+    );
+}
+
+// returns the index of the I/O APIC covering the `gsi`.
+fn find_io_apic(io_apics: &mut [Option<IoApic>], gsi: u32) -> Result<usize, ApicError> {
+    io_apics
+        .iter_mut()
+        .position(|io_apic| match io_apic {
+            Some(io_apic) => io_apic.covers(gsi),
+            None => false,
+        })
+        .ok_or(ApicError::GsiNotCovered(gsi))
+}
+
+/// Returns true when the interrupts go through the APIC.
+pub(crate) fn is_enabled() -> bool {
+    ENABLED.load(Ordering::SeqCst)
+}
+
+/// Signals the end of interrupt to the local APIC.
+///
+/// It's called from the interrupt handlers, which already run with the
+/// interrupts disabled.
+pub(crate) fn end_of_interrupt() {
+    if let Some(local_apic) = LOCAL_APIC.lock().as_mut() {
+        local_apic.write(LAPIC_EOI, 0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{serial_print, serial_println};
+    use x86_64::instructions::interrupts;
+    #[test_case]
+    fn local_apic() {
+        serial_print!("apic::local_apic... ");
+        assert!(super::is_enabled());
+        let version = interrupts::without_interrupts(|| {
+            let local_apic = super::LOCAL_APIC.lock();
+            local_apic
+                .as_ref()
+                .expect("no local apic")
+                .read(super::LAPIC_VERSION)
+        });
+        // integrated APIC versions.
+        assert!((0x10..=0x15).contains(&(version & 0xff)));
+        serial_println!("[ok]");
+    }
+    #[test_case]
+    fn find_io_apic() {
+        serial_print!("apic::find_io_apic... ");
+        let gsi = u32::max_value();
+        let (timer, outside) = interrupts::without_interrupts(|| {
+            let mut io_apics = super::IO_APICS.lock();
+            (
+                super::find_io_apic(&mut *io_apics, 0),
+                super::find_io_apic(&mut *io_apics, gsi),
+            )
+        });
+        assert!(timer.is_ok());
+        assert_eq!(outside, Err(super::ApicError::GsiNotCovered(gsi)));
+        serial_println!("[ok]");
+    }
+}
@@ -1,7 +1,8 @@
 //! Global Descriptor Table handling
+use core::cell::UnsafeCell;
 use lazy_static::lazy_static;
 use x86_64::{
-    instructions::{segmentation::set_cs, tables::load_tss},
+    instructions::{interrupts, segmentation::set_cs, tables::load_tss},
     structures::{
         gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector},
         tss::TaskStateSegment,
@@ -21,7 +22,7 @@ lazy_static! {
     static ref GDT: (GlobalDescriptorTable, Selectors) = {
         let mut gdt = GlobalDescriptorTable::new();
         let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
+        let tss_selector = gdt.add_entry(Descriptor::tss_segment(unsafe { &*TSS.0.get() }));
         (
             gdt,
             Selectors {
@@ -37,15 +38,29 @@ struct Selectors {
     tss_selector: SegmentSelector,
 }
 
-const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+/// Interrupt stack table index of the double fault handler stack.
+pub(crate) const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 
 lazy_static! {
-    static ref TSS: TaskStateSegment = {
+    static ref TSS: Tss = {
         let mut tss = TaskStateSegment::new();
+        // Boot time stack, until the memory manager provides the guarded one.
         const STACK_SIZE: usize = 4096;
         static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
         let stack_end = VirtAddr::from_ptr(unsafe { &STACK }) + STACK_SIZE;
         tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = stack_end;
-        tss
+        Tss(UnsafeCell::new(tss))
     };
 }
+
+/// Task state segment, which CPU reads the interrupt stacks from.
+struct Tss(UnsafeCell<TaskStateSegment>);
+
+unsafe impl Sync for Tss {}
+
+/// Switches the double fault handler stack to `stack_end`.
+pub(crate) fn set_double_fault_stack(stack_end: VirtAddr) {
+    interrupts::without_interrupts(|| unsafe {
+        (*TSS.0.get()).interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = stack_end;
+    });
+}
@@ -2,6 +2,7 @@
 extern crate pic8259_simple;
 use self::pic8259_simple::ChainedPics;
 use crate::println;
+use core::sync::atomic::{AtomicBool, Ordering};
 use lazy_static::lazy_static;
 use spin::Mutex;
 use x86_64::{
@@ -13,6 +14,7 @@ pub(crate) fn init() {
     IDT.load();
     // Initialize the interrupt controller.
     unsafe { PICS.lock().initialize() };
+    PICS_INITIALIZED.store(true, Ordering::SeqCst);
     // Enable the hardware interrupts.
     x86_64::instructions::interrupts::enable();
 }
@@ -21,10 +23,21 @@ lazy_static! {
     pub static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
         idt.breakpoint.set_handler_fn(breakpoint_handler);
-        idt.double_fault.set_handler_fn(double_fault_handler);
+        // The double fault handler runs on its own stack, as the stack
+        // overflow leaves no room for the exception frame.
+        unsafe {
+            idt.double_fault
+                .set_handler_fn(double_fault_handler)
+                .set_stack_index(crate::gdt::DOUBLE_FAULT_IST_INDEX);
+        }
         idt.page_fault.set_handler_fn(page_fault_handler);
         idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
         idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
+        idt[InterruptIndex::Rtc.as_usize()].set_handler_fn(rtc_interrupt_handler);
+        idt[usize::from(crate::apic::SPURIOUS_VECTOR)].set_handler_fn(spurious_interrupt_handler);
+        // The masked 8259 PIC still raises the spurious IRQ 7 and 15.
+        idt[usize::from(PIC_1_SPURIOUS)].set_handler_fn(primary_spurious_handler);
+        idt[usize::from(PIC_2_SPURIOUS)].set_handler_fn(secondary_spurious_handler);
         idt
     };
 }
@@ -37,7 +50,13 @@ extern "x86-interrupt" fn double_fault_handler(
     stack_frame: &mut InterruptStackFrame,
     _error_code: u64,
 ) -> ! {
-    panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
+    use x86_64::registers::control::Cr2;
+    // The stack overflow ends up here, as CPU can't push the page fault
+    // exception frame onto the overflowed stack.
+    match crate::memory::find_guard(Cr2::read()) {
+        Some(guard) => panic!("EXCEPTION: DOUBLE FAULT: {}\n{:#?}", guard, stack_frame),
+        None => panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame),
+    }
 }
 
 extern "x86-interrupt" fn page_fault_handler(
@@ -45,7 +64,14 @@ extern "x86-interrupt" fn page_fault_handler(
     error_code: PageFaultErrorCode,
 ) {
     use x86_64::registers::control::Cr2;
-    println!("EXCEPTION: PAGE FAULT");
+    // The lazily backed pages are allocated on the first touch.
+    if crate::memory::handle_page_fault(Cr2::read(), error_code) {
+        return;
+    }
+    match crate::memory::find_guard(Cr2::read()) {
+        Some(guard) => println!("EXCEPTION: PAGE FAULT: {}", guard),
+        None => println!("EXCEPTION: PAGE FAULT"),
+    }
     println!("Accessed Address: {:?}", Cr2::read());
     println!("Error Code: {:?}", error_code);
     println!("{:#?}", stack_frame);
@@ -53,31 +79,71 @@ extern "x86-interrupt" fn page_fault_handler(
 }
 
 extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
-    }
+    crate::time::tick();
+    end_of_interrupt(InterruptIndex::Timer);
 }
 
 extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
     let mut port = Port::new(0x60);
     let scancode: u8 = unsafe { port.read() };
     crate::task::keyboard::add_scancode(scancode);
+    end_of_interrupt(InterruptIndex::Keyboard);
+}
+
+extern "x86-interrupt" fn rtc_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
+    crate::rtc::handle_interrupt();
+    end_of_interrupt(InterruptIndex::Rtc);
+}
+
+extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
+    // The local APIC doesn't expect the EOI for the spurious interrupt.
+}
+
+extern "x86-interrupt" fn primary_spurious_handler(_stack_frame: &mut InterruptStackFrame) {
+    // The spurious IRQ 7 is not in service, and expects no EOI.
+    if in_service(PIC_1_COMMAND, 7) {
+        unsafe { PICS.lock().notify_end_of_interrupt(PIC_1_SPURIOUS) };
+    }
+}
+
+extern "x86-interrupt" fn secondary_spurious_handler(_stack_frame: &mut InterruptStackFrame) {
+    // The spurious IRQ 15 still needs the EOI on the primary PIC, which
+    // took the cascade IRQ 2 for it.
+    let vector = if in_service(PIC_2_COMMAND, 7) {
+        PIC_2_SPURIOUS
+    } else {
+        PIC_1_OFFSET
+    };
+    unsafe { PICS.lock().notify_end_of_interrupt(vector) };
+}
+
+// returns true when the PIC's in-service register has the `line` set.
+fn in_service(command: u16, line: u8) -> bool {
+    let mut port: Port<u8> = Port::new(command);
     unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
+        port.write(PIC_READ_ISR);
+        port.read() & 1 << line != 0
+    }
+}
+
+fn end_of_interrupt(index: InterruptIndex) {
+    if crate::apic::is_enabled() {
+        crate::apic::end_of_interrupt();
+    } else {
+        unsafe { PICS.lock().notify_end_of_interrupt(index.as_u8()) };
     }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
-enum InterruptIndex {
+pub(crate) enum InterruptIndex {
     Timer = PIC_1_OFFSET,
     Keyboard,
+    Rtc = PIC_2_OFFSET,
 }
 
 impl InterruptIndex {
-    fn as_u8(self) -> u8 {
+    pub(crate) fn as_u8(self) -> u8 {
         self as u8
     }
     fn as_usize(self) -> usize {
@@ -87,9 +153,51 @@ impl InterruptIndex {
 
 const PIC_1_OFFSET: u8 = 32;
 const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
+const PIC_1_SPURIOUS: u8 = PIC_1_OFFSET + 7;
+const PIC_2_SPURIOUS: u8 = PIC_2_OFFSET + 7;
+const PIC_1_COMMAND: u16 = 0x20;
+const PIC_2_COMMAND: u16 = 0xa0;
+// OCW3 command to read the in-service register.
+const PIC_READ_ISR: u8 = 0x0b;
 
 static PICS: Mutex<ChainedPics> =
     Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
+static PICS_INITIALIZED: AtomicBool = AtomicBool::new(false);
+
+/// Returns true when the 8259 PIC pair is remapped away from the CPU
+/// exception vectors.
+pub(crate) fn pics_initialized() -> bool {
+    PICS_INITIALIZED.load(Ordering::SeqCst)
+}
+
+/// Unmasks the ISA `irq` on the 8259 PIC, unless the APIC took over, which
+/// routes all the IRQs in use already.
+pub(crate) fn enable_irq(irq: u8) {
+    if crate::apic::is_enabled() {
+        return;
+    }
+    let (port, line) = if irq < 8 {
+        (0x21, irq)
+    } else {
+        (0xa1, irq - 8)
+    };
+    let mut mask: Port<u8> = Port::new(port);
+    unsafe { mask.write(mask.read() & !(1 << line)) };
+    if irq >= 8 {
+        // the secondary PIC cascades on the primary IRQ 2.
+        enable_irq(2);
+    }
+}
+
+/// Masks all the 8259 PIC lines, once the APIC takes over.
+pub(crate) fn disable_pics() {
+    let mut primary: Port<u8> = Port::new(0x21);
+    let mut secondary: Port<u8> = Port::new(0xa1);
+    unsafe {
+        primary.write(0xff);
+        secondary.write(0xff);
+    }
+}
 
 #[cfg(test)]
 mod tests {
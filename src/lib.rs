@@ -27,6 +27,7 @@
 //!     // Initialize the kernel.
 //!     rustos::init();
 //!     rustos::memory::init(boot_info);
+//!     rustos::init_apic();
 //!
 //!     // Spawn async task(s).
 //!     let mut executor = task::Executor::new();
@@ -66,6 +67,7 @@
 #![cfg_attr(test, no_main)]
 #![feature(alloc_error_handler)]
 #![feature(alloc_layout_extra)]
+#![feature(asm)]
 #![feature(const_fn)]
 #![feature(const_in_array_repeat_expressions)]
 #![feature(custom_test_frameworks)]
@@ -78,24 +80,40 @@ extern crate lazy_static;
 extern crate spin;
 extern crate x86_64;
 
-mod allocator;
+pub mod allocator;
+mod apic;
 mod gdt;
 mod interrupts;
 pub mod memory;
+pub mod rtc;
 pub mod serial;
 pub mod task;
+pub mod time;
 pub mod vga;
 
 use core::panic::PanicInfo;
 
 // re-exports.
+pub use allocator::HEAP_MAX_SIZE;
 pub use allocator::HEAP_SIZE;
 pub use allocator::HEAP_START;
 
 /// Kernel initialization function.
 pub fn init() {
     gdt::init();
+    time::init();
+    rtc::init();
     interrupts::init();
+    memory::init_supervisor_protection();
+}
+
+/// Switches the interrupt controller from the 8259 PIC to the APIC, when
+/// the ACPI MADT has one.
+///
+/// It's called after `init` and `memory::init`, as the APIC registers are
+/// mapped through the VMM.
+pub fn init_apic() {
+    apic::init();
 }
 
 /// hlt instruction based kernel loop.
@@ -133,6 +151,7 @@ entry_point!(test_kernel);
 fn test_kernel(boot_info: &'static BootInfo) -> ! {
     init();
     memory::init(boot_info);
+    init_apic();
     test_main();
     hlt_loop();
 }
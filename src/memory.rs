@@ -1,77 +0,0 @@
-//! Memory mapper and the frame allocator
-use bootloader::bootinfo::{BootInfo, MemoryMap, MemoryRegionType};
-use x86_64::{
-    structures::paging::{
-        FrameAllocator, OffsetPageTable, PageTable, PhysFrame, Size4KiB, UnusedPhysFrame,
-    },
-    PhysAddr, VirtAddr,
-};
-
-/// Kernel memory manager initialization function.
-pub fn init(boot_info: &'static BootInfo) {
-    // frame allocator.
-    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
-    let mut mapper = unsafe { init_page_table(phys_mem_offset) };
-    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
-    crate::allocator::init(&mut mapper, &mut frame_allocator).expect("allocator failed");
-}
-
-/// Initializes the page table.
-///
-/// # Safety
-///
-/// This function should NOT be called.  It's public just for the integration
-/// testing purpose.
-pub unsafe fn init_page_table(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
-    let level_4_table = active_level_4_table(physical_memory_offset);
-    OffsetPageTable::new(level_4_table, physical_memory_offset)
-}
-
-unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut PageTable {
-    use x86_64::registers::control::Cr3;
-
-    let (level_4_table_frame, _) = Cr3::read();
-    let phys = level_4_table_frame.start_address();
-    let virt = physical_memory_offset + phys.as_u64();
-    let page_table_ptr: *mut PageTable = virt.as_mut_ptr();
-
-    &mut *page_table_ptr
-}
-
-/// Memory frame allocator.
-struct BootInfoFrameAllocator {
-    memory_map: &'static MemoryMap,
-    next: usize,
-}
-
-unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
-    fn allocate_frame(&mut self) -> Option<UnusedPhysFrame> {
-        let frame = self.usable_frames().nth(self.next);
-        self.next += 1;
-        frame
-    }
-}
-
-impl BootInfoFrameAllocator {
-    /// Create a memory frame allocator with the memory map.
-    ///
-    /// # Safety
-    ///
-    /// This function is unsafe because the caller must guarantee that the passed
-    /// memory map is valid.  The main requirement is that all frames that are marked
-    /// as `USABLE` in it are really unused.
-    unsafe fn init(memory_map: &'static MemoryMap) -> Self {
-        Self {
-            memory_map,
-            next: 0,
-        }
-    }
-    fn usable_frames(&self) -> impl Iterator<Item = UnusedPhysFrame> {
-        let regions = self.memory_map.iter();
-        let usable_regions = regions.filter(|r| r.region_type == MemoryRegionType::Usable);
-        let addr_ranges = usable_regions.map(|r| r.range.start_addr()..r.range.end_addr());
-        let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096));
-        let frames = frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)));
-        frames.map(|f| unsafe { UnusedPhysFrame::new(f) })
-    }
-}
@@ -0,0 +1,204 @@
+//! [Buddy] system physical memory allocator
+//!
+//! [buddy]: https://en.wikipedia.org/wiki/Buddy_memory_allocation
+use x86_64::{
+    structures::paging::{PageSize, PhysFrame, Size4KiB},
+    PhysAddr, VirtAddr,
+};
+
+/// The largest block order, e.g. 2^10 frames, or 4MiB.
+pub const MAX_ORDER: usize = 10;
+
+const ORDERS: usize = MAX_ORDER + 1;
+const FRAME_SIZE: u64 = Size4KiB::SIZE;
+
+/// Physical memory zones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Zone {
+    /// Below 16MiB, for the legacy ISA DMA.
+    Dma,
+    /// Below 4GiB, for the 32-bit DMA capable devices.
+    Dma32,
+    /// Anywhere in the physical memory.
+    Normal,
+}
+
+const ZONE_COUNT: usize = 3;
+const ZONES: [Zone; ZONE_COUNT] = [Zone::Dma, Zone::Dma32, Zone::Normal];
+
+impl Zone {
+    /// The end of the zone's physical address range.
+    pub fn end_addr(self) -> u64 {
+        match self {
+            Self::Dma => 16 * 1024 * 1024,
+            Self::Dma32 => 4 * 1024 * 1024 * 1024,
+            Self::Normal => u64::max_value(),
+        }
+    }
+    fn containing(addr: u64) -> Self {
+        *ZONES
+            .iter()
+            .find(|zone| addr < zone.end_addr())
+            .unwrap_or(&Self::Normal)
+    }
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// Buddy system physical memory allocator.
+///
+/// It keeps the free lists of 2^order frame blocks for each zone.
+/// The free list nodes live in the free blocks themselves and are
+/// accessed through the physical memory offset.
+pub struct BuddyAllocator {
+    free_lists: [[Option<PhysAddr>; ORDERS]; ZONE_COUNT],
+    physical_memory_offset: VirtAddr,
+    free: usize,
+}
+
+impl BuddyAllocator {
+    /// Create an empty buddy allocator.
+    ///
+    /// # Safety
+    ///
+    /// The caller must guarantee that the whole physical memory is mapped
+    /// at `physical_memory_offset`.
+    pub(super) unsafe fn new(physical_memory_offset: VirtAddr) -> Self {
+        Self {
+            free_lists: [[None; ORDERS]; ZONE_COUNT],
+            physical_memory_offset,
+            free: 0,
+        }
+    }
+    /// Adds the free physical memory range to the allocator.
+    ///
+    /// # Safety
+    ///
+    /// The caller must guarantee that the range is unused and not managed by
+    /// other allocators.
+    pub(super) unsafe fn add_region(&mut self, start: PhysAddr, end: PhysAddr) {
+        let mut start = start.align_up(FRAME_SIZE).as_u64();
+        let end = end.align_down(FRAME_SIZE).as_u64();
+        while start < end {
+            let limit = end.min(Zone::containing(start).end_addr());
+            let mut order = MAX_ORDER;
+            while start % Self::block_size(order) != 0 || start + Self::block_size(order) > limit {
+                order -= 1;
+            }
+            self.push(Zone::containing(start), order, PhysAddr::new(start));
+            self.free += 1 << order;
+            start += Self::block_size(order);
+        }
+    }
+    /// Allocates 2^`order` physically contiguous frames inside the `zone`.
+    ///
+    /// It tries the higher zones first, and never falls back to the
+    /// `Zone::Dma` zone, to keep the precious low memory for the devices
+    /// which really need it.
+    pub fn allocate(&mut self, order: usize, zone: Zone) -> Option<PhysFrame> {
+        if order > MAX_ORDER {
+            return None;
+        }
+        let lowest = if zone == Zone::Dma { 0 } else { 1 };
+        for &zone in ZONES[lowest..=zone.index()].iter().rev() {
+            let found = (order..ORDERS).find(|&o| self.free_lists[zone.index()][o].is_some());
+            if let Some(mut current) = found {
+                let addr = self.pop(zone, current).unwrap();
+                while current > order {
+                    current -= 1;
+                    self.push(zone, current, addr + Self::block_size(current));
+                }
+                self.free -= 1 << order;
+                return Some(PhysFrame::containing_address(addr));
+            }
+        }
+        None
+    }
+    /// Returns 2^`order` frames starting at `frame` to the allocator, and
+    /// coalesces it with its free buddies.
+    ///
+    /// # Safety
+    ///
+    /// The caller must guarantee that the block was allocated by `allocate`
+    /// with the same `order` and it's not in use anymore.
+    pub unsafe fn deallocate(&mut self, frame: PhysFrame, order: usize) {
+        let mut addr = frame.start_address().as_u64();
+        let zone = Zone::containing(addr);
+        self.free += 1 << order;
+        let mut order = order;
+        while order < MAX_ORDER {
+            let buddy = addr ^ Self::block_size(order);
+            if !self.remove(zone, order, PhysAddr::new(buddy)) {
+                break;
+            }
+            addr = addr.min(buddy);
+            order += 1;
+        }
+        self.push(zone, order, PhysAddr::new(addr));
+    }
+    /// Returns the number of free frames.
+    pub fn free_frames(&self) -> usize {
+        self.free
+    }
+    fn block_size(order: usize) -> u64 {
+        FRAME_SIZE << order
+    }
+    fn next(&mut self, addr: PhysAddr) -> &mut Option<PhysAddr> {
+        let virt = self.physical_memory_offset + addr.as_u64();
+        unsafe { &mut *virt.as_mut_ptr::<Option<PhysAddr>>() }
+    }
+    fn push(&mut self, zone: Zone, order: usize, addr: PhysAddr) {
+        let head = self.free_lists[zone.index()][order].take();
+        *self.next(addr) = head;
+        self.free_lists[zone.index()][order] = Some(addr);
+    }
+    fn pop(&mut self, zone: Zone, order: usize) -> Option<PhysAddr> {
+        let addr = self.free_lists[zone.index()][order]?;
+        self.free_lists[zone.index()][order] = self.next(addr).take();
+        Some(addr)
+    }
+    fn remove(&mut self, zone: Zone, order: usize, addr: PhysAddr) -> bool {
+        let mut prev: Option<PhysAddr> = None;
+        let mut current = self.free_lists[zone.index()][order];
+        while let Some(node) = current {
+            let next = *self.next(node);
+            if node == addr {
+                match prev {
+                    None => self.free_lists[zone.index()][order] = next,
+                    Some(prev) => *self.next(prev) = next,
+                }
+                return true;
+            }
+            prev = current;
+            current = next;
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Zone;
+    use crate::{serial_print, serial_println};
+    #[test_case]
+    fn allocate_dma_zone() {
+        serial_print!("memory::buddy::allocate_dma_zone... ");
+        let free = super::super::free_contiguous_frames();
+        let frame = super::super::allocate_contiguous(2, Zone::Dma).expect("out of DMA memory");
+        let addr = frame.start_address().as_u64();
+        assert_eq!(addr % (4 * 4096), 0);
+        assert!(addr + 4 * 4096 <= Zone::Dma.end_addr());
+        unsafe { super::super::deallocate_contiguous(frame, 2) };
+        assert_eq!(super::super::free_contiguous_frames(), free);
+        serial_println!("[ok]");
+    }
+    #[test_case]
+    fn allocate_above_dma_zone() {
+        serial_print!("memory::buddy::allocate_above_dma_zone... ");
+        let frame = super::super::allocate_contiguous(0, Zone::Normal).expect("out of memory");
+        assert!(frame.start_address().as_u64() >= Zone::Dma.end_addr());
+        unsafe { super::super::deallocate_contiguous(frame, 0) };
+        serial_println!("[ok]");
+    }
+}
@@ -0,0 +1,239 @@
+//! Copy-on-write mappings
+//!
+//! The shared frame is mapped read-only with the `COW` flag in all the
+//! page tables sharing it, and the write fault copies it to the private
+//! writable frame, unless it's the last reference.
+use super::BitmapFrameAllocator;
+use core::ptr;
+use x86_64::{
+    instructions::tlb,
+    registers::control::Cr3,
+    structures::paging::{
+        mapper::MapToError, page_table::PageTableEntry, FrameAllocator, FrameDeallocator, Mapper,
+        OffsetPageTable, Page, PageSize, PageTable, PageTableFlags, PhysFrame, Size4KiB,
+        UnusedPhysFrame,
+    },
+    VirtAddr,
+};
+
+/// Copy-on-write page table flag, one of the available bits.
+pub const COW: PageTableFlags = PageTableFlags::BIT_9;
+
+/// Copy-on-write mapping errors.
+#[derive(Debug)]
+pub enum CowError {
+    /// The source page is not mapped by the 4KiB page.
+    PageNotMapped,
+    /// The source frame is not the allocated RAM frame, e.g. the MMIO.
+    NotAllocated,
+    /// The destination page mapping failed.
+    Map(MapToError<Size4KiB>),
+    /// The frame reference count overflowed.
+    TooManyReferences,
+}
+
+impl From<MapToError<Size4KiB>> for CowError {
+    fn from(err: MapToError<Size4KiB>) -> Self {
+        Self::Map(err)
+    }
+}
+
+/// Shares the frame mapped at `src_page` in `src` with `dst` at `dst_page`,
+/// both copy-on-write.
+///
+/// # Safety
+///
+/// The caller must guarantee that the whole physical memory is mapped at
+/// the physical memory offset of both page tables.
+pub unsafe fn share(
+    src: &mut OffsetPageTable,
+    src_page: Page<Size4KiB>,
+    dst: &mut OffsetPageTable,
+    dst_page: Page<Size4KiB>,
+) -> Result<(), CowError> {
+    super::with_frame_allocator(|frame_allocator| {
+        let (frame, flags) = mark(src, src_page, frame_allocator)?;
+        map_shared(dst, dst_page, frame, flags, frame_allocator)
+    })
+}
+
+/// Maps `dst_page` to the frame mapped at `src_page` in the kernel page
+/// table, both copy-on-write, e.g. for the cheap snapshot.
+pub fn snapshot(src_page: Page<Size4KiB>, dst_page: Page<Size4KiB>) -> Result<(), CowError> {
+    super::with_mapper(|mapper, frame_allocator| unsafe {
+        let (frame, flags) = mark(mapper, src_page, frame_allocator)?;
+        map_shared(mapper, dst_page, frame, flags, frame_allocator)
+    })
+}
+
+// makes the `page` copy-on-write and returns the frame and the new flags.
+unsafe fn mark(
+    table: &mut OffsetPageTable,
+    page: Page<Size4KiB>,
+    frame_allocator: &BitmapFrameAllocator,
+) -> Result<(PhysFrame, PageTableFlags), CowError> {
+    let offset = super::physical_memory_offset();
+    let entry = entry_mut(table.level_4_table(), offset, page).ok_or(CowError::PageNotMapped)?;
+    let frame = entry.frame().map_err(|_| CowError::PageNotMapped)?;
+    // only the frame allocator frames are reference counted.
+    if frame_allocator.ref_count(frame) == 0 {
+        return Err(CowError::NotAllocated);
+    }
+    let mut flags = entry.flags();
+    if flags.contains(PageTableFlags::WRITABLE) {
+        flags = (flags - PageTableFlags::WRITABLE) | COW;
+        entry.set_flags(flags);
+        tlb::flush(page.start_address());
+    }
+    Ok((frame, flags))
+}
+
+unsafe fn map_shared(
+    table: &mut OffsetPageTable,
+    page: Page<Size4KiB>,
+    frame: PhysFrame,
+    flags: PageTableFlags,
+    frame_allocator: &mut BitmapFrameAllocator,
+) -> Result<(), CowError> {
+    if !frame_allocator.share(frame) {
+        return Err(CowError::TooManyReferences);
+    }
+    match table.map_to(page, UnusedPhysFrame::new(frame), flags, frame_allocator) {
+        Ok(flush) => {
+            flush.flush();
+            Ok(())
+        }
+        Err(err) => {
+            frame_allocator.deallocate_frame(UnusedPhysFrame::new(frame));
+            Err(err.into())
+        }
+    }
+}
+
+/// Resolves the write fault at `addr` on the copy-on-write page of the
+/// active page table, and returns true when it's resolved.
+///
+/// # Safety
+///
+/// The caller must guarantee that the whole physical memory is mapped at
+/// `offset` and nothing else modifies the active page table.
+pub(super) unsafe fn handle_write_fault(
+    addr: VirtAddr,
+    offset: VirtAddr,
+    frame_allocator: &mut BitmapFrameAllocator,
+) -> bool {
+    let (level_4_table_frame, _) = Cr3::read();
+    let table = offset + level_4_table_frame.start_address().as_u64();
+    let entry = match entry_mut(
+        &mut *table.as_mut_ptr(),
+        offset,
+        Page::containing_address(addr),
+    ) {
+        Some(entry) => entry,
+        None => return false,
+    };
+    let flags = entry.flags();
+    if !flags.contains(COW) {
+        return false;
+    }
+    let flags = (flags - COW) | PageTableFlags::WRITABLE;
+    let frame = match entry.frame() {
+        Ok(frame) => frame,
+        Err(_) => return false,
+    };
+    if frame_allocator.ref_count(frame) > 1 {
+        let copy = match frame_allocator.allocate_frame() {
+            Some(copy) => copy,
+            None => return false,
+        };
+        let src = (offset + frame.start_address().as_u64()).as_ptr::<u8>();
+        let dst = (offset + copy.start_address().as_u64()).as_mut_ptr::<u8>();
+        ptr::copy_nonoverlapping(src, dst, Size4KiB::SIZE as usize);
+        entry.set_addr(copy.start_address(), flags);
+        // drops the reference to the shared frame.
+        frame_allocator.deallocate_frame(UnusedPhysFrame::new(frame));
+    } else {
+        // the last reference.
+        entry.set_flags(flags);
+    }
+    tlb::flush(addr);
+    true
+}
+
+// returns the level 1 page table entry of the mapped `page`.
+unsafe fn entry_mut<'a>(
+    level_4_table: &'a mut PageTable,
+    offset: VirtAddr,
+    page: Page<Size4KiB>,
+) -> Option<&'a mut PageTableEntry> {
+    let mut table = level_4_table;
+    for &index in &[page.p4_index(), page.p3_index(), page.p2_index()] {
+        let entry = &table[index];
+        if !entry.flags().contains(PageTableFlags::PRESENT)
+            || entry.flags().contains(PageTableFlags::HUGE_PAGE)
+        {
+            return None;
+        }
+        table = &mut *(offset + entry.addr().as_u64()).as_mut_ptr::<PageTable>();
+    }
+    let entry = &mut table[page.p1_index()];
+    if !entry.flags().contains(PageTableFlags::PRESENT) {
+        return None;
+    }
+    Some(entry)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::vmm::{self, RegionKind};
+    use super::CowError;
+    use crate::{serial_print, serial_println};
+    use x86_64::{
+        structures::paging::{Page, PageTableFlags},
+        PhysAddr, VirtAddr,
+    };
+    #[test_case]
+    fn write_after_snapshot() {
+        serial_print!("memory::cow::write_after_snapshot... ");
+        let flags = PageTableFlags::WRITABLE;
+        let src = vmm::allocate("cow source", RegionKind::Anonymous, 1, flags).expect("no region");
+        let dst = vmm::reserve("cow snapshot", RegionKind::Anonymous, 1, flags).expect("no region");
+        let (src_ptr, dst_ptr) = (src.start.as_mut_ptr::<u64>(), dst.start.as_mut_ptr::<u64>());
+        unsafe { src_ptr.write_volatile(1) };
+        super::snapshot(
+            Page::containing_address(src.start),
+            Page::containing_address(dst.start),
+        )
+        .expect("snapshot failed");
+        assert_eq!(unsafe { dst_ptr.read_volatile() }, 1);
+        // both get the private copies on write.
+        unsafe { src_ptr.write_volatile(2) };
+        unsafe { dst_ptr.write_volatile(3) };
+        assert_eq!(unsafe { src_ptr.read_volatile() }, 2);
+        assert_eq!(unsafe { dst_ptr.read_volatile() }, 3);
+        unsafe {
+            vmm::release(dst.start).expect("release failed");
+            vmm::release(src.start).expect("release failed");
+        }
+        serial_println!("[ok]");
+    }
+    #[test_case]
+    fn snapshot_mmio() {
+        serial_print!("memory::cow::snapshot_mmio... ");
+        // the VGA text buffer.
+        let vga = unsafe { super::super::map_mmio::<[u8; 4096]>(PhysAddr::new(0xb8000), 4096) }
+            .expect("mmio mapping failed");
+        let flags = PageTableFlags::WRITABLE;
+        let dst = vmm::reserve("cow snapshot", RegionKind::Anonymous, 1, flags).expect("no region");
+        let src = VirtAddr::from_ptr(&*vga);
+        match super::snapshot(
+            Page::containing_address(src),
+            Page::containing_address(dst.start),
+        ) {
+            Err(CowError::NotAllocated) => {}
+            result => panic!("unexpected result: {:?}", result),
+        }
+        unsafe { vmm::release(dst.start).expect("release failed") };
+        serial_println!("[ok]");
+    }
+}
@@ -0,0 +1,118 @@
+//! Kernel ELF image protection
+//!
+//! It walks the kernel program headers and remaps the loaded segments with
+//! the W^X page flags, e.g. `.text` read-only and executable, `.rodata`
+//! read-only and `.data`/`.bss` writable, both non-executable.
+use core::slice;
+use x86_64::{
+    structures::paging::{Mapper, OffsetPageTable, Page, PageTableFlags, Size4KiB},
+    VirtAddr,
+};
+
+const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+
+/// Kernel image protection errors.
+#[derive(Debug)]
+pub(super) enum ProtectError {
+    /// The linker provided ELF header is not mapped.
+    HeaderNotMapped(VirtAddr),
+    /// The ELF header is corrupted.
+    BadHeader,
+    /// The segment is both writable and executable.
+    WritableAndExecutable(VirtAddr),
+    /// The segment page is not mapped.
+    SegmentNotMapped(VirtAddr),
+}
+
+extern "C" {
+    // The linker provided ELF header of the kernel image.
+    static __ehdr_start: Header;
+}
+
+#[allow(dead_code)]
+#[repr(C)]
+struct Header {
+    ident: [u8; 16],
+    kind: u16,
+    machine: u16,
+    version: u32,
+    entry: u64,
+    phoff: u64,
+    shoff: u64,
+    flags: u32,
+    ehsize: u16,
+    phentsize: u16,
+    phnum: u16,
+    shentsize: u16,
+    shnum: u16,
+    shstrndx: u16,
+}
+
+#[allow(dead_code)]
+#[repr(C)]
+struct ProgramHeader {
+    kind: u32,
+    flags: u32,
+    offset: u64,
+    vaddr: u64,
+    paddr: u64,
+    filesz: u64,
+    memsz: u64,
+    align: u64,
+}
+
+/// Remaps the loaded kernel segments with the W^X page flags.
+///
+/// It fails when the ELF header is not mapped, e.g. stripped by the linker
+/// script, as the segments are only found through it.
+///
+/// # Safety
+///
+/// The caller must guarantee that the no-execute page flag is enabled in
+/// `EFER`, as it's a reserved bit otherwise.
+pub(super) unsafe fn protect_kernel(mapper: &mut OffsetPageTable) -> Result<(), ProtectError> {
+    let addr = VirtAddr::from_ptr(&__ehdr_start);
+    if mapper
+        .translate_page(Page::<Size4KiB>::containing_address(addr))
+        .is_err()
+    {
+        return Err(ProtectError::HeaderNotMapped(addr));
+    }
+    let header = &__ehdr_start;
+    if header.ident[..4] != ELF_MAGIC
+        || usize::from(header.phentsize) != core::mem::size_of::<ProgramHeader>()
+    {
+        return Err(ProtectError::BadHeader);
+    }
+    let phdrs = (addr + header.phoff).as_ptr::<ProgramHeader>();
+    let phdrs = slice::from_raw_parts(phdrs, usize::from(header.phnum));
+    for phdr in phdrs
+        .iter()
+        .filter(|phdr| phdr.kind == PT_LOAD && phdr.memsz > 0)
+    {
+        if phdr.flags & (PF_W | PF_X) == PF_W | PF_X {
+            return Err(ProtectError::WritableAndExecutable(VirtAddr::new(
+                phdr.vaddr,
+            )));
+        }
+        let mut flags = PageTableFlags::PRESENT;
+        if phdr.flags & PF_W != 0 {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if phdr.flags & PF_X == 0 {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
+        let start = Page::<Size4KiB>::containing_address(VirtAddr::new(phdr.vaddr));
+        let end = Page::containing_address(VirtAddr::new(phdr.vaddr + phdr.memsz - 1));
+        for page in Page::range_inclusive(start, end) {
+            mapper
+                .update_flags(page, flags)
+                .map_err(|_| ProtectError::SegmentNotMapped(page.start_address()))?
+                .flush();
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,264 @@
+//! Bitmap based physical frame allocator
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use core::slice;
+use x86_64::{
+    structures::paging::{
+        FrameAllocator, FrameDeallocator, PageSize, PhysFrame, Size4KiB, UnusedPhysFrame,
+    },
+    PhysAddr, VirtAddr,
+};
+
+const FRAME_SIZE: u64 = Size4KiB::SIZE;
+const BITS_PER_WORD: usize = 64;
+
+/// Bitmap based physical frame allocator.
+///
+/// Each bit in the bitmap represents a 4KiB physical frame, set for
+/// the used, or unusable, frames and cleared for the free frames.
+/// The bitmap itself lives in the first usable region big enough
+/// to hold it and is accessed through the physical memory offset.
+///
+/// It also keeps the reference count of each frame, for the frames shared
+/// by the copy-on-write mappings, right after the bitmap.  It's zero for
+/// the frames not handed out by `allocate_frame`, e.g. the reserved ones.
+pub struct BitmapFrameAllocator {
+    bitmap: &'static mut [u64],
+    refs: &'static mut [u16],
+    frames: usize,
+    free: usize,
+    next: usize,
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<UnusedPhysFrame> {
+        let words = self.bitmap.len();
+        for i in 0..words {
+            let index = (self.next + i) % words;
+            let word = self.bitmap[index];
+            if word == !0 {
+                continue;
+            }
+            let bit = (!word).trailing_zeros() as usize;
+            let frame = index * BITS_PER_WORD + bit;
+            if frame >= self.frames {
+                continue;
+            }
+            self.bitmap[index] |= 1 << bit;
+            self.refs[frame] = 1;
+            self.free -= 1;
+            self.next = index;
+            let frame = PhysFrame::containing_address(Self::frame_addr(frame));
+            return Some(unsafe { UnusedPhysFrame::new(frame) });
+        }
+        None
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BitmapFrameAllocator {
+    fn deallocate_frame(&mut self, frame: UnusedPhysFrame) {
+        let frame = Self::frame_index(frame.start_address());
+        let (index, bit) = (frame / BITS_PER_WORD, frame % BITS_PER_WORD);
+        assert!(frame < self.frames, "frame out of range");
+        assert!(self.bitmap[index] & 1 << bit != 0, "double frame free");
+        // the shared frame is freed by the last owner.
+        if self.refs[frame] > 1 {
+            self.refs[frame] -= 1;
+            return;
+        }
+        self.refs[frame] = 0;
+        self.bitmap[index] &= !(1 << bit);
+        self.free += 1;
+        if index < self.next {
+            self.next = index;
+        }
+    }
+}
+
+impl BitmapFrameAllocator {
+    /// Create a bitmap frame allocator with the memory map.
+    ///
+    /// # Safety
+    ///
+    /// This function is unsafe because the caller must guarantee that the passed
+    /// memory map is valid and the whole physical memory is mapped at
+    /// `physical_memory_offset`.  The main requirement is that all frames that
+    /// are marked as `USABLE` in it are really unused.
+    pub(super) unsafe fn init(
+        memory_map: &'static MemoryMap,
+        physical_memory_offset: VirtAddr,
+    ) -> Self {
+        let usable = || {
+            memory_map
+                .iter()
+                .filter(|r| r.region_type == MemoryRegionType::Usable)
+        };
+        let end = usable().map(|r| r.range.end_addr()).max().unwrap_or(0);
+        let frames = (end / FRAME_SIZE) as usize;
+        let words = (frames + BITS_PER_WORD - 1) / BITS_PER_WORD;
+        // the bitmap followed by the reference counts, in the whole frames.
+        let bitmap_size = ((words * 8 + frames * 2) as u64 + FRAME_SIZE - 1) & !(FRAME_SIZE - 1);
+        let region = usable()
+            .find(|r| r.range.end_addr() - r.range.start_addr() >= bitmap_size)
+            .expect("no usable region for the frame bitmap");
+        let bitmap_start = region.range.start_addr();
+        let ptr: *mut u64 = (physical_memory_offset + bitmap_start).as_mut_ptr();
+        let bitmap = slice::from_raw_parts_mut(ptr, words);
+        for word in bitmap.iter_mut() {
+            *word = !0;
+        }
+        let refs = slice::from_raw_parts_mut(ptr.add(words) as *mut u16, frames);
+        for count in refs.iter_mut() {
+            *count = 0;
+        }
+        let mut allocator = Self {
+            bitmap,
+            refs,
+            frames,
+            free: 0,
+            next: 0,
+        };
+        for r in usable() {
+            allocator.set_range(r.range.start_addr(), r.range.end_addr(), false);
+        }
+        allocator.set_range(bitmap_start, bitmap_start + bitmap_size, true);
+        allocator
+    }
+    /// Returns the number of free frames.
+    pub fn free_frames(&self) -> usize {
+        self.free
+    }
+    /// Returns the number of frames covered by the bitmap.
+    pub fn total_frames(&self) -> usize {
+        self.frames
+    }
+    /// Takes up to `max_frames` free frames out of the physical range between
+    /// `start` and `end`, and passes each contiguous run of those to `f`.
+    ///
+    /// Returns the number of frames taken.
+    pub(super) fn take_free_frames<F>(
+        &mut self,
+        start: u64,
+        end: u64,
+        max_frames: usize,
+        mut f: F,
+    ) -> usize
+    where
+        F: FnMut(PhysAddr, PhysAddr),
+    {
+        let start = Self::frame_index(PhysAddr::new(start).align_up(FRAME_SIZE));
+        let end = Self::frame_index(PhysAddr::new(end).align_down(FRAME_SIZE)).min(self.frames);
+        let mut taken = 0;
+        let mut run: Option<usize> = None;
+        for frame in start..end {
+            let (index, bit) = (frame / BITS_PER_WORD, frame % BITS_PER_WORD);
+            let free = taken < max_frames && self.bitmap[index] & 1 << bit == 0;
+            if free {
+                self.bitmap[index] |= 1 << bit;
+                self.free -= 1;
+                taken += 1;
+                run.get_or_insert(frame);
+            } else if let Some(run_start) = run.take() {
+                f(Self::frame_addr(run_start), Self::frame_addr(frame));
+            }
+        }
+        if let Some(run_start) = run {
+            f(Self::frame_addr(run_start), Self::frame_addr(end));
+        }
+        taken
+    }
+    /// Adds a reference to the allocated `frame`, which will be freed by
+    /// the last `deallocate_frame`.
+    ///
+    /// Returns false when the frame is not allocated by `allocate_frame`, or
+    /// has too many references already.
+    pub(super) fn share(&mut self, frame: PhysFrame) -> bool {
+        if self.ref_count(frame) == 0 {
+            return false;
+        }
+        let frame = Self::frame_index(frame.start_address());
+        match self.refs[frame].checked_add(1) {
+            Some(refs) => {
+                self.refs[frame] = refs;
+                true
+            }
+            None => false,
+        }
+    }
+    /// Returns the number of references to the `frame`, or zero when it's
+    /// not allocated by `allocate_frame`.
+    pub fn ref_count(&self, frame: PhysFrame) -> usize {
+        let frame = Self::frame_index(frame.start_address());
+        let (index, bit) = (frame / BITS_PER_WORD, frame % BITS_PER_WORD);
+        if frame >= self.frames || self.bitmap[index] & 1 << bit == 0 {
+            return 0;
+        }
+        usize::from(self.refs[frame])
+    }
+    // marks the frames in the `start`..`end` range.  The used range covers
+    // the partial frames at both ends, and the free range doesn't.
+    fn set_range(&mut self, start: u64, end: u64, used: bool) {
+        let (start, end) = (PhysAddr::new(start), PhysAddr::new(end));
+        let (start, end) = if used {
+            (start.align_down(FRAME_SIZE), end.align_up(FRAME_SIZE))
+        } else {
+            (start.align_up(FRAME_SIZE), end.align_down(FRAME_SIZE))
+        };
+        let (start, end) = (Self::frame_index(start), Self::frame_index(end));
+        for frame in start..end.min(self.frames) {
+            let (index, bit) = (frame / BITS_PER_WORD, frame % BITS_PER_WORD);
+            let was_used = self.bitmap[index] & 1 << bit != 0;
+            if used && !was_used {
+                self.bitmap[index] |= 1 << bit;
+                self.free -= 1;
+            } else if !used && was_used {
+                self.bitmap[index] &= !(1 << bit);
+                self.free += 1;
+            }
+        }
+    }
+    fn frame_index(addr: PhysAddr) -> usize {
+        (addr.as_u64() / FRAME_SIZE) as usize
+    }
+    fn frame_addr(frame: usize) -> PhysAddr {
+        PhysAddr::new(frame as u64 * FRAME_SIZE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{serial_print, serial_println};
+    use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, UnusedPhysFrame};
+    #[test_case]
+    fn allocate_and_deallocate_frame() {
+        serial_print!("memory::frame::allocate_and_deallocate_frame... ");
+        super::super::with_frame_allocator(|allocator| {
+            let free = allocator.free_frames();
+            let frame = allocator.allocate_frame().expect("out of frames");
+            let addr = frame.start_address();
+            assert_eq!(allocator.free_frames(), free - 1);
+            allocator.deallocate_frame(frame);
+            assert_eq!(allocator.free_frames(), free);
+            let frame = allocator.allocate_frame().expect("out of frames");
+            assert_eq!(frame.start_address(), addr);
+            allocator.deallocate_frame(frame);
+        });
+        serial_println!("[ok]");
+    }
+    #[test_case]
+    fn share_overflow() {
+        serial_print!("memory::frame::share_overflow... ");
+        super::super::with_frame_allocator(|allocator| {
+            let free = allocator.free_frames();
+            let frame = allocator.allocate_frame().expect("out of frames");
+            let phys = *frame;
+            while allocator.share(phys) {}
+            assert_eq!(allocator.ref_count(phys), usize::from(u16::max_value()));
+            for _ in 0..u16::max_value() {
+                allocator.deallocate_frame(unsafe { UnusedPhysFrame::new(phys) });
+            }
+            assert_eq!(allocator.ref_count(phys), 0);
+            assert_eq!(allocator.free_frames(), free);
+        });
+        serial_println!("[ok]");
+    }
+}
@@ -0,0 +1,106 @@
+//! Guard pages around the kernel heap and stacks
+use super::vmm::{VmmError, MAX_REGIONS};
+use core::fmt;
+use spin::Mutex;
+use x86_64::{
+    instructions::interrupts,
+    structures::paging::{Page, Size4KiB},
+    VirtAddr,
+};
+
+// up to two guard pages for each region, and the boot stack one.
+const MAX_GUARDS: usize = 2 * MAX_REGIONS + 1;
+
+static GUARDS: Mutex<[Option<Guard>; MAX_GUARDS]> = Mutex::new([None; MAX_GUARDS]);
+
+/// Guarded memory kinds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GuardKind {
+    /// Guard page beneath a stack.
+    Stack,
+    /// Guard page around the heap.
+    Heap,
+}
+
+/// Unmapped guard page.
+#[derive(Debug, Clone, Copy)]
+pub struct Guard {
+    /// The guard page.
+    pub page: Page<Size4KiB>,
+    /// The guarded memory kind.
+    pub kind: GuardKind,
+    /// The guarded memory name.
+    pub name: &'static str,
+}
+
+impl fmt::Display for Guard {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            GuardKind::Stack => write!(f, "stack overflow in {}", self.name),
+            GuardKind::Heap => write!(f, "out of bounds access to {}", self.name),
+        }
+    }
+}
+
+/// Registers the unmapped guard `page`.
+pub(super) fn register(
+    page: Page<Size4KiB>,
+    kind: GuardKind,
+    name: &'static str,
+) -> Result<(), VmmError> {
+    interrupts::without_interrupts(|| {
+        let mut guards = GUARDS.lock();
+        let slot = guards
+            .iter_mut()
+            .find(|guard| guard.is_none())
+            .ok_or(VmmError::TooManyRegions)?;
+        *slot = Some(Guard { page, kind, name });
+        Ok(())
+    })
+}
+
+/// Unregisters the guard `page`.
+pub(super) fn unregister(page: Page<Size4KiB>) {
+    interrupts::without_interrupts(|| {
+        let mut guards = GUARDS.lock();
+        for slot in guards.iter_mut() {
+            if slot.map_or(false, |guard| guard.page == page) {
+                *slot = None;
+            }
+        }
+    })
+}
+
+/// Returns the guard page containing `addr`, if any.
+///
+/// It's called by the exception handlers, so it gives up instead of
+/// spinning on the lock.
+pub fn find(addr: VirtAddr) -> Option<Guard> {
+    let page = Page::containing_address(addr);
+    let guards = GUARDS.try_lock()?;
+    guards
+        .iter()
+        .flatten()
+        .find(|guard| guard.page == page)
+        .copied()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::GuardKind;
+    use crate::{serial_print, serial_println};
+    use x86_64::VirtAddr;
+    #[test_case]
+    fn guard_pages() {
+        serial_print!("memory::guard::guard_pages... ");
+        let heap_start = VirtAddr::new(crate::allocator::heap_start() as u64);
+        let guard = super::find(heap_start - 1u64).expect("no heap guard");
+        assert_eq!(guard.kind, GuardKind::Heap);
+        let stack = super::super::alloc_stack("test stack", 2).expect("no stack");
+        unsafe { *(stack.top - 8u64).as_mut_ptr::<u64>() = 42 };
+        let guard = super::find(stack.bottom - 1u64).expect("no stack guard");
+        assert_eq!(guard.kind, GuardKind::Stack);
+        assert_eq!(guard.name, "test stack");
+        serial_println!("[ok]");
+    }
+}
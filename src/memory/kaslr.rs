@@ -0,0 +1,69 @@
+//! Kernel address space layout randomization
+//!
+//! The xorshift64* generator seeded by `RDRAND`, or by `RDTSC` when the
+//! CPU doesn't support it, picks the VMM region base of each region kind
+//! once at boot.
+use super::vmm::{RegionKind, REGION_KINDS, VMM_END, VMM_START};
+use core::arch::x86_64::{__cpuid, _rdrand64_step, _rdtsc};
+use spin::{Mutex, Once};
+use x86_64::{
+    instructions::interrupts,
+    structures::paging::{PageSize, Size2MiB},
+};
+
+static STATE: Mutex<u64> = Mutex::new(0);
+static REGION_BASES: Once<[u64; REGION_KINDS]> = Once::new();
+
+/// Returns the next pseudo random number.
+pub fn random() -> u64 {
+    interrupts::without_interrupts(|| {
+        let mut state = STATE.lock();
+        if *state == 0 {
+            *state = seed() | 1;
+        }
+        // xorshift64*
+        let mut x = *state;
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        *state = x;
+        x.wrapping_mul(0x_2545_f491_4f6c_dd1d)
+    })
+}
+
+/// Returns the base address of the `kind` regions.
+///
+/// Each kind gets its own slice of the VMM window, and the base is at the
+/// random 2MiB boundary in the lower half of it, which leaves the room for
+/// the regions above.
+pub(super) fn region_base(kind: RegionKind) -> u64 {
+    let bases = REGION_BASES.call_once(|| {
+        let slice = (VMM_END - VMM_START) / REGION_KINDS as u64;
+        let mut bases = [0; REGION_KINDS];
+        for (i, base) in bases.iter_mut().enumerate() {
+            let start = VMM_START + i as u64 * slice;
+            *base = random_addr(start, start + slice / 2, Size2MiB::SIZE);
+        }
+        bases
+    });
+    bases[kind.index()]
+}
+
+// returns the random `align` aligned address in `start..end`.
+fn random_addr(start: u64, end: u64, align: u64) -> u64 {
+    (start + random() % (end - start)) & !(align - 1)
+}
+
+fn seed() -> u64 {
+    // CPUID.01H:ECX.RDRAND[bit 30]
+    if unsafe { __cpuid(1) }.ecx & (1 << 30) != 0 {
+        let mut seed = 0;
+        // RDRAND may run out of the entropy for a while.
+        for _ in 0..10 {
+            if unsafe { _rdrand64_step(&mut seed) } == 1 {
+                return seed;
+            }
+        }
+    }
+    unsafe { _rdtsc() }
+}
@@ -0,0 +1,122 @@
+//! Physical memory map inspection
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use conquer_once::spin::OnceCell;
+use core::fmt;
+use x86_64::PhysAddr;
+
+static MEMORY_MAP: OnceCell<&'static MemoryMap> = OnceCell::uninit();
+
+/// Physical memory region reported by the bootloader.
+#[derive(Debug, Clone, Copy)]
+pub struct PhysRegion {
+    /// The region type.
+    pub kind: MemoryRegionType,
+    /// The start address of the region.
+    pub start: PhysAddr,
+    /// The end address of the region, exclusive.
+    pub end: PhysAddr,
+}
+
+impl PhysRegion {
+    /// Returns the region size in bytes.
+    pub fn size(&self) -> u64 {
+        self.end.as_u64() - self.start.as_u64()
+    }
+}
+
+impl fmt::Display for PhysRegion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "[{:#014x}-{:#014x}] {:>8}KiB {:?}",
+            self.start.as_u64(),
+            self.end.as_u64(),
+            self.size() / 1024,
+            self.kind,
+        )
+    }
+}
+
+/// Physical memory summary.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Summary {
+    /// The total size of all the regions in bytes.
+    pub total: u64,
+    /// The usable memory size in bytes.
+    pub usable: u64,
+    /// The memory used by the kernel and the bootloader in bytes, e.g. the
+    /// kernel image and the page tables.
+    pub used: u64,
+    /// The memory reserved by the firmware and the hardware in bytes.
+    pub reserved: u64,
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        const MIB: u64 = 1024 * 1024;
+        write!(
+            f,
+            "{}MiB total, {}MiB usable, {}MiB used, {}MiB reserved",
+            self.total / MIB,
+            self.usable / MIB,
+            self.used / MIB,
+            self.reserved / MIB,
+        )
+    }
+}
+
+pub(super) fn init(memory_map: &'static MemoryMap) {
+    MEMORY_MAP
+        .try_init_once(|| memory_map)
+        .expect("memory map should only be initialized once");
+}
+
+/// Returns the physical memory regions reported by the bootloader.
+pub fn regions() -> impl Iterator<Item = PhysRegion> {
+    let memory_map = MEMORY_MAP.try_get().expect("memory uninitialized");
+    memory_map.iter().map(|r| PhysRegion {
+        kind: r.region_type,
+        start: PhysAddr::new(r.range.start_addr()),
+        end: PhysAddr::new(r.range.end_addr()),
+    })
+}
+
+/// Returns the physical memory summary.
+pub fn summary() -> Summary {
+    regions().fold(Summary::default(), |mut summary, region| {
+        summary.total += region.size();
+        match region.kind {
+            MemoryRegionType::Usable => summary.usable += region.size(),
+            MemoryRegionType::Reserved
+            | MemoryRegionType::AcpiReclaimable
+            | MemoryRegionType::AcpiNvs
+            | MemoryRegionType::BadMemory
+            | MemoryRegionType::UnknownBios(_)
+            | MemoryRegionType::UnknownUefi(_) => summary.reserved += region.size(),
+            _ => summary.used += region.size(),
+        }
+        summary
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{serial_print, serial_println};
+    use bootloader::bootinfo::MemoryRegionType;
+    #[test_case]
+    fn summary() {
+        serial_print!("memory::map::summary... ");
+        let summary = super::summary();
+        let usable: u64 = super::regions()
+            .filter(|r| r.kind == MemoryRegionType::Usable)
+            .map(|r| r.size())
+            .sum();
+        assert!(usable > 0);
+        assert_eq!(summary.usable, usable);
+        assert_eq!(
+            summary.total,
+            summary.usable + summary.used + summary.reserved
+        );
+        serial_println!("[ok]");
+    }
+}
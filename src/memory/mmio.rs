@@ -0,0 +1,114 @@
+//! Memory mapped I/O
+//!
+//! It maps the physical device registers into the VMM window with the
+//! uncached page flags, and unmaps those when the handle is dropped.
+use super::vmm::{self, Region, RegionKind, VmmError};
+use core::{
+    mem,
+    ops::{Deref, DerefMut},
+};
+use x86_64::{
+    structures::paging::{PageSize, PageTableFlags, Size4KiB},
+    PhysAddr,
+};
+
+/// Memory mapped device registers, laid out as `T`.
+///
+/// `T` is expected to be built from the `Volatile` fields, e.g.
+/// `[Volatile<u32>; 4]` or a `#[repr(C)]` register block, so that all the
+/// register accesses go through the volatile reads and writes.
+#[derive(Debug)]
+pub struct Mmio<T> {
+    region: Region,
+    phys: PhysAddr,
+    len: usize,
+    ptr: *mut T,
+}
+
+unsafe impl<T: Send> Send for Mmio<T> {}
+unsafe impl<T: Sync> Sync for Mmio<T> {}
+
+/// Maps `len` bytes of the device memory at `phys` as `T`.
+///
+/// # Safety
+///
+/// The caller must guarantee that `phys` points to the device registers
+/// laid out as `T`, which are not mapped by anybody else.
+pub unsafe fn map_mmio<T>(phys: PhysAddr, len: usize) -> Result<Mmio<T>, VmmError> {
+    assert!(len >= mem::size_of::<T>(), "mmio too small for the type");
+    let offset = phys.as_u64() % Size4KiB::SIZE;
+    let pages = (offset + len as u64 + Size4KiB::SIZE - 1) / Size4KiB::SIZE;
+    let flags = PageTableFlags::WRITABLE
+        | PageTableFlags::NO_CACHE
+        | PageTableFlags::WRITE_THROUGH
+        | PageTableFlags::NO_EXECUTE;
+    let region = vmm::reserve("mmio", RegionKind::Mmio, pages, flags)?;
+    if let Err(err) = vmm::map_phys(&region, phys.align_down(Size4KiB::SIZE)) {
+        vmm::release(region.start).expect("release failed");
+        return Err(err);
+    }
+    let ptr = (region.start + offset).as_mut_ptr::<T>();
+    assert_eq!(ptr as usize % mem::align_of::<T>(), 0, "misaligned mmio");
+    Ok(Mmio {
+        region,
+        phys,
+        len,
+        ptr,
+    })
+}
+
+impl<T> Mmio<T> {
+    /// Returns the physical address of the registers.
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.phys
+    }
+    /// Returns the mapped length in bytes.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+    /// Returns true when nothing is mapped.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl<T> Deref for Mmio<T> {
+    type Target = T;
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.ptr }
+    }
+}
+
+impl<T> DerefMut for Mmio<T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *self.ptr }
+    }
+}
+
+impl<T> Drop for Mmio<T> {
+    fn drop(&mut self) {
+        unsafe { vmm::release(self.region.start).expect("mmio release failed") };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{serial_print, serial_println};
+    use volatile::Volatile;
+    use x86_64::{PhysAddr, VirtAddr};
+    #[test_case]
+    fn map_vga_buffer() {
+        serial_print!("memory::mmio::map_vga_buffer... ");
+        // the last character cell of the identity mapped VGA text buffer.
+        let phys = PhysAddr::new(0xb8000 + 2 * (80 * 25 - 1));
+        let mut mmio = unsafe { super::map_mmio::<Volatile<u16>>(phys, 2) }.expect("no mmio");
+        let start = VirtAddr::from_ptr(&*mmio);
+        mmio.write(0x0f21);
+        let identity = phys.as_u64() as *const u16;
+        assert_eq!(unsafe { identity.read_volatile() }, 0x0f21);
+        assert_eq!(mmio.read(), 0x0f21);
+        drop(mmio);
+        assert!(super::vmm::find(start).is_none());
+        serial_println!("[ok]");
+    }
+}
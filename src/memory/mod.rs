@@ -0,0 +1,486 @@
+//! Memory mapper and the frame allocator
+use bootloader::bootinfo::{BootInfo, MemoryRegionType};
+use conquer_once::spin::OnceCell;
+use spin::Mutex;
+use x86_64::{
+    instructions::{interrupts, tlb},
+    registers::{
+        control::{Cr0, Cr0Flags},
+        model_specific::{Efer, EferFlags},
+    },
+    structures::idt::PageFaultErrorCode,
+    structures::paging::{
+        mapper::{MapToError, UnmapError},
+        FrameAllocator, FrameDeallocator, Mapper, MapperAllSizes, OffsetPageTable, Page, PageSize,
+        PageTable, PageTableFlags, PhysFrame, Size1GiB, Size2MiB, Size4KiB, TranslateResult,
+        UnusedPhysFrame,
+    },
+    PhysAddr, VirtAddr,
+};
+
+mod buddy;
+pub mod cow;
+mod elf;
+mod frame;
+mod guard;
+#[cfg(feature = "kaslr")]
+mod kaslr;
+mod map;
+mod mmio;
+pub mod space;
+mod stack;
+mod user;
+pub mod vmm;
+
+/// Re-exports.
+pub use buddy::{BuddyAllocator, Zone, MAX_ORDER};
+pub use frame::BitmapFrameAllocator;
+pub use guard::{find as find_guard, Guard, GuardKind};
+pub use map::{regions, summary, PhysRegion, Summary};
+pub use mmio::{map_mmio, Mmio};
+pub use space::AddressSpace;
+pub use stack::{alloc_stack, Stack};
+pub(crate) use user::init as init_supervisor_protection;
+pub use user::{has_smap, has_smep, user_copy_from, user_copy_to};
+pub use vmm::{Region, RegionKind, VmmError};
+
+/// The buddy allocator order of the 2MiB huge frames.
+pub const HUGE_PAGE_ORDER: usize = 9;
+
+/// Double fault handler stack size in pages.
+const DOUBLE_FAULT_STACK_PAGES: u64 = 4;
+
+/// Physical memory size handed to the buddy allocator above the
+/// `Zone::Dma` zone, which is handed over entirely.
+pub const CONTIGUOUS_POOL_SIZE: usize = 16 * 1024 * 1024; // 16MiB
+
+// The kernel heap grows through these while holding the heap allocator lock,
+// so never allocate from the heap while holding them.
+static PHYSICAL_MEMORY_OFFSET: OnceCell<VirtAddr> = OnceCell::uninit();
+static MAPPER: Mutex<Option<OffsetPageTable<'static>>> = Mutex::new(None);
+static FRAME_ALLOCATOR: Mutex<Option<BitmapFrameAllocator>> = Mutex::new(None);
+static BUDDY_ALLOCATOR: Mutex<Option<BuddyAllocator>> = Mutex::new(None);
+
+/// Kernel memory manager initialization function.
+pub fn init(boot_info: &'static BootInfo) {
+    // frame allocator.
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    PHYSICAL_MEMORY_OFFSET
+        .try_init_once(|| phys_mem_offset)
+        .expect("memory::init should only be called once");
+    map::init(&boot_info.memory_map);
+    space::init();
+    let mut mapper = unsafe { init_page_table(phys_mem_offset) };
+    // W^X on the kernel image, no-execute on its physical memory alias, and
+    // the read-only pages for the kernel, too.
+    unsafe {
+        Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE));
+        elf::protect_kernel(&mut mapper).expect("kernel image protection failed");
+        protect_physical_memory(&mut mapper, phys_mem_offset);
+        Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT));
+    }
+    let mut frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    space::init_kernel_tables(mapper.level_4_table(), &mut frame_allocator)
+        .expect("kernel page table allocation failed");
+    let heap = reserve_heap().expect("heap reservation failed");
+    crate::allocator::init(&mut mapper, &mut frame_allocator, heap.start)
+        .expect("allocator failed");
+    // buddy allocator for the physically contiguous frames.
+    let mut buddy_allocator = unsafe { BuddyAllocator::new(phys_mem_offset) };
+    let dma_end = Zone::Dma.end_addr();
+    let pool_frames = CONTIGUOUS_POOL_SIZE / 4096;
+    let mut add_region = |start, end| unsafe { buddy_allocator.add_region(start, end) };
+    frame_allocator.take_free_frames(0, dma_end, usize::max_value(), &mut add_region);
+    frame_allocator.take_free_frames(dma_end, u64::max_value(), pool_frames, &mut add_region);
+    interrupts::without_interrupts(|| {
+        MAPPER.lock().replace(mapper);
+        FRAME_ALLOCATOR.lock().replace(frame_allocator);
+        BUDDY_ALLOCATOR.lock().replace(buddy_allocator);
+    });
+    init_guards();
+    crate::serial_println!("memory: {}", summary());
+}
+
+const HEAP_PAGES: u64 = crate::HEAP_MAX_SIZE as u64 / Size4KiB::SIZE;
+const HEAP_FLAGS: PageTableFlags = PageTableFlags::from_bits_truncate(
+    PageTableFlags::WRITABLE.bits() | PageTableFlags::NO_EXECUTE.bits(),
+);
+
+/// Reserves the kernel heap region at the random address.
+#[cfg(feature = "kaslr")]
+fn reserve_heap() -> Result<Region, VmmError> {
+    vmm::reserve("kernel heap", RegionKind::Heap, HEAP_PAGES, HEAP_FLAGS)
+}
+
+/// Reserves the kernel heap region at `HEAP_START`.
+#[cfg(not(feature = "kaslr"))]
+fn reserve_heap() -> Result<Region, VmmError> {
+    let start = VirtAddr::new(crate::HEAP_START as u64);
+    vmm::reserve_at(
+        "kernel heap",
+        RegionKind::Heap,
+        start,
+        HEAP_PAGES,
+        HEAP_FLAGS,
+    )
+}
+
+/// Registers the guard page beneath the boot stack, makes it non-executable,
+/// and moves the double fault handler onto the guarded stack.
+fn init_guards() {
+    // The bootloader leaves the page beneath the boot stack unmapped, and
+    // reports the boot stack frames in the memory map.
+    let marker = 0u8;
+    let current = Page::containing_address(VirtAddr::from_ptr(&marker));
+    let stack_pages = regions()
+        .filter(|r| r.kind == MemoryRegionType::KernelStack)
+        .map(|r| r.size())
+        .sum::<u64>()
+        / Size4KiB::SIZE;
+    let mut page = current;
+    with_mapper(|mapper, _| {
+        while mapper.translate_page(page).is_ok() {
+            page = page - 1;
+        }
+        // make the whole boot stack non-executable.
+        let top = (page + stack_pages).max(current);
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        for stack_page in Page::range_inclusive(page + 1, top) {
+            mapper
+                .update_flags(stack_page, flags)
+                .expect("boot stack update failed")
+                .flush();
+        }
+    });
+    guard::register(page, GuardKind::Stack, "kernel stack").expect("guard registration failed");
+    let stack = alloc_stack("double fault stack", DOUBLE_FAULT_STACK_PAGES)
+        .expect("double fault stack allocation failed");
+    crate::gdt::set_double_fault_stack(stack.top);
+    #[cfg(feature = "kaslr")]
+    crate::serial_println!(
+        "kaslr: heap at {:#x}, double fault stack at {:?}",
+        crate::allocator::heap_start(),
+        stack.bottom,
+    );
+}
+
+/// Makes the physical memory window non-executable, as it aliases all the
+/// frames, e.g. the kernel heap.  It stays writable, as the page tables and
+/// the frame allocators are accessed through it.
+///
+/// # Safety
+///
+/// The caller must guarantee that the no-execute page flag is enabled in
+/// `EFER`, and the whole physical memory is mapped at `offset`.
+unsafe fn protect_physical_memory(mapper: &mut OffsetPageTable, offset: VirtAddr) {
+    let end = offset + regions().map(|r| r.end.as_u64()).max().unwrap_or(0);
+    let mut addr = offset;
+    while addr < end {
+        let size = set_no_execute(mapper.level_4_table(), offset, addr);
+        addr = addr.align_down(size) + size;
+    }
+    tlb::flush_all();
+}
+
+// sets the no-execute flag on the entry mapping `addr`, and returns the
+// size it maps, or the size of the unmapped range.
+unsafe fn set_no_execute(level_4_table: &mut PageTable, offset: VirtAddr, addr: VirtAddr) -> u64 {
+    let indexes = [
+        addr.p4_index(),
+        addr.p3_index(),
+        addr.p2_index(),
+        addr.p1_index(),
+    ];
+    let sizes = [1 << 39, Size1GiB::SIZE, Size2MiB::SIZE, Size4KiB::SIZE];
+    let mut table = level_4_table;
+    for (level, &index) in indexes.iter().enumerate() {
+        let entry = &mut table[index];
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            return sizes[level];
+        }
+        if level == 3 || (level > 0 && flags.contains(PageTableFlags::HUGE_PAGE)) {
+            entry.set_flags(flags | PageTableFlags::NO_EXECUTE);
+            return sizes[level];
+        }
+        table = &mut *(offset + entry.addr().as_u64()).as_mut_ptr();
+    }
+    unreachable!()
+}
+
+/// Initializes the page table.
+///
+/// # Safety
+///
+/// The caller must guarantee that the whole physical memory is mapped at
+/// `physical_memory_offset` and this is called only once, as the returned
+/// mapper owns the active level 4 table.
+unsafe fn init_page_table(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+    let level_4_table = active_level_4_table(physical_memory_offset);
+    OffsetPageTable::new(level_4_table, physical_memory_offset)
+}
+
+unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut PageTable {
+    use x86_64::registers::control::Cr3;
+
+    let (level_4_table_frame, _) = Cr3::read();
+    let phys = level_4_table_frame.start_address();
+    let virt = physical_memory_offset + phys.as_u64();
+    let page_table_ptr: *mut PageTable = virt.as_mut_ptr();
+
+    &mut *page_table_ptr
+}
+
+/// Maps the kernel `page` to a newly allocated frame with `flags`.
+///
+/// The user pages are mapped through `AddressSpace::map_user` instead, so
+/// that no kernel page table entry is user accessible.
+pub(crate) fn map_page(
+    page: Page<Size4KiB>,
+    flags: PageTableFlags,
+) -> Result<(), MapToError<Size4KiB>> {
+    assert!(
+        !flags.contains(PageTableFlags::USER_ACCESSIBLE),
+        "user accessible kernel page"
+    );
+    interrupts::without_interrupts(|| {
+        let mut mapper = MAPPER.lock();
+        let mut frame_allocator = FRAME_ALLOCATOR.lock();
+        // the heap may grow before the memory manager is fully initialized.
+        let (mapper, frame_allocator) = match (mapper.as_mut(), frame_allocator.as_mut()) {
+            (Some(mapper), Some(frame_allocator)) => (mapper, frame_allocator),
+            _ => return Err(MapToError::FrameAllocationFailed),
+        };
+        let frame = frame_allocator
+            .allocate_frame()
+            .ok_or(MapToError::FrameAllocationFailed)?;
+        mapper.map_to(page, frame, flags, frame_allocator)?.flush();
+        Ok(())
+    })
+}
+
+/// Returns the virtual address the whole physical memory is mapped at.
+pub fn physical_memory_offset() -> VirtAddr {
+    *PHYSICAL_MEMORY_OFFSET
+        .try_get()
+        .expect("memory uninitialized")
+}
+
+/// Handles the page fault at `addr`, and returns true when it's resolved,
+/// e.g. the first touch of the lazily backed region or the write to the
+/// copy-on-write page.
+///
+/// It's called by the page fault handler, so it gives up instead of
+/// spinning on the locks.
+pub(crate) fn handle_page_fault(addr: VirtAddr, error_code: PageFaultErrorCode) -> bool {
+    let offset = match PHYSICAL_MEMORY_OFFSET.try_get() {
+        Ok(offset) => *offset,
+        Err(_) => return false,
+    };
+    // classifies the fault first, so that the unrelated ones, e.g. the guard
+    // page hits, never contend for the locks.
+    if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        if !error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+            return false;
+        }
+        // the copy-on-write fault only updates the faulting page table entry,
+        // so it works while the faulting code holds the mapper.
+        let mut frame_allocator = match FRAME_ALLOCATOR.try_lock() {
+            Some(frame_allocator) => frame_allocator,
+            None => return false,
+        };
+        return match frame_allocator.as_mut() {
+            Some(frame_allocator) => unsafe {
+                cow::handle_write_fault(addr, offset, frame_allocator)
+            },
+            None => false,
+        };
+    }
+    let region = match vmm::find(addr) {
+        Some(region) if region.lazy => region,
+        _ => return false,
+    };
+    let (mut mapper, mut frame_allocator) = match (MAPPER.try_lock(), FRAME_ALLOCATOR.try_lock()) {
+        (Some(mapper), Some(frame_allocator)) => (mapper, frame_allocator),
+        _ => return false,
+    };
+    let (mapper, frame_allocator) = match (mapper.as_mut(), frame_allocator.as_mut()) {
+        (Some(mapper), Some(frame_allocator)) => (mapper, frame_allocator),
+        _ => return false,
+    };
+    let frame = match frame_allocator.allocate_frame() {
+        Some(frame) => frame,
+        None => return false,
+    };
+    let phys = *frame;
+    let page = Page::containing_address(addr);
+    match mapper.map_to(page, frame, region.flags, frame_allocator) {
+        Ok(flush) => flush.flush(),
+        Err(_) => {
+            unsafe { frame_allocator.deallocate_frame(UnusedPhysFrame::new(phys)) };
+            return false;
+        }
+    }
+    let ptr = page.start_address().as_mut_ptr::<u8>();
+    unsafe { core::ptr::write_bytes(ptr, 0, Size4KiB::SIZE as usize) };
+    true
+}
+
+/// Returns true when the CPU supports the 1GiB pages.
+pub fn has_1gib_pages() -> bool {
+    // CPUID.80000001H:EDX.Page1GB[bit 26]
+    let cpuid = unsafe { core::arch::x86_64::__cpuid(0x8000_0001) };
+    cpuid.edx & (1 << 26) != 0
+}
+
+/// Maps the `page` to the `frame` with `flags`, for any page size.
+///
+/// # Safety
+///
+/// The caller must guarantee that the frame is not used by anything else.
+pub(crate) unsafe fn map_frame<S: PageSize>(
+    page: Page<S>,
+    frame: PhysFrame<S>,
+    flags: PageTableFlags,
+) -> Result<(), MapToError<S>>
+where
+    OffsetPageTable<'static>: Mapper<S>,
+{
+    with_mapper(|mapper, frame_allocator| {
+        let frame = UnusedPhysFrame::new(frame);
+        mapper.map_to(page, frame, flags, frame_allocator)?.flush();
+        Ok(())
+    })
+}
+
+/// Unmaps the `page` and returns the backing frame to the frame allocator.
+///
+/// # Safety
+///
+/// The caller must guarantee that nothing references the page, as the
+/// backing frame will be handed out again by the frame allocator.
+pub unsafe fn unmap(page: Page<Size4KiB>) -> Result<(), UnmapError> {
+    with_mapper(|mapper, frame_allocator| {
+        let (frame, flush) = mapper.unmap(page)?;
+        flush.flush();
+        frame_allocator.deallocate_frame(UnusedPhysFrame::new(frame));
+        Ok(())
+    })
+}
+
+/// Unmaps the pages of any size in the `start`..`end` range, skipping the
+/// unmapped ones, and returns the backing frames in case of `deallocate`.
+///
+/// The 2MiB frames are returned to the buddy allocator, as those come from
+/// there, and the 1GiB pages fail with `deallocate`, as those are never
+/// allocated.
+pub(crate) unsafe fn unmap_range(
+    start: VirtAddr,
+    end: VirtAddr,
+    deallocate: bool,
+) -> Result<(), UnmapError> {
+    let mut addr = start;
+    while addr < end {
+        let (size, huge_frame) =
+            with_mapper(|mapper, frame_allocator| match mapper.translate(addr) {
+                TranslateResult::Frame4KiB { .. } => {
+                    let (frame, flush) =
+                        mapper.unmap(Page::<Size4KiB>::containing_address(addr))?;
+                    flush.flush();
+                    if deallocate {
+                        frame_allocator.deallocate_frame(UnusedPhysFrame::new(frame));
+                    }
+                    Ok((Size4KiB::SIZE, None))
+                }
+                TranslateResult::Frame2MiB { .. } => {
+                    let (frame, flush) =
+                        mapper.unmap(Page::<Size2MiB>::containing_address(addr))?;
+                    flush.flush();
+                    Ok((Size2MiB::SIZE, Some(frame)))
+                }
+                TranslateResult::Frame1GiB { frame, .. } => {
+                    // the 1GiB frames are never allocated, so never owned.
+                    if deallocate {
+                        return Err(UnmapError::InvalidFrameAddress(frame.start_address()));
+                    }
+                    let (_, flush) = mapper.unmap(Page::<Size1GiB>::containing_address(addr))?;
+                    flush.flush();
+                    Ok((Size1GiB::SIZE, None))
+                }
+                _ => Ok((Size4KiB::SIZE, None)),
+            })?;
+        if let (Some(frame), true) = (huge_frame, deallocate) {
+            let frame = PhysFrame::containing_address(frame.start_address());
+            deallocate_contiguous(frame, HUGE_PAGE_ORDER);
+        }
+        addr = addr.align_down(size) + size;
+    }
+    Ok(())
+}
+
+/// Translates `addr` with the kernel page table.
+pub fn translate_addr(addr: VirtAddr) -> Option<PhysAddr> {
+    with_mapper(|mapper, _| mapper.translate_addr(addr))
+}
+
+/// Returns the number of free physical frames.
+pub fn free_frames() -> usize {
+    with_frame_allocator(|frame_allocator| frame_allocator.free_frames())
+}
+
+/// Allocates 2^`order` physically contiguous frames inside the `zone`.
+pub fn allocate_contiguous(order: usize, zone: Zone) -> Option<PhysFrame> {
+    with_buddy_allocator(|buddy_allocator| buddy_allocator.allocate(order, zone))
+}
+
+/// Returns 2^`order` physically contiguous frames to the buddy allocator.
+///
+/// # Safety
+///
+/// The caller must guarantee that the frames were allocated by
+/// `allocate_contiguous` with the same `order` and are not in use anymore.
+pub unsafe fn deallocate_contiguous(frame: PhysFrame, order: usize) {
+    with_buddy_allocator(|buddy_allocator| buddy_allocator.deallocate(frame, order))
+}
+
+/// Returns the number of free frames in the buddy allocator.
+pub fn free_contiguous_frames() -> usize {
+    with_buddy_allocator(|buddy_allocator| buddy_allocator.free_frames())
+}
+
+/// Runs `f` with the kernel page table mapper and the frame allocator.
+pub(crate) fn with_mapper<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut OffsetPageTable<'static>, &mut BitmapFrameAllocator) -> R,
+{
+    interrupts::without_interrupts(|| {
+        let mut mapper = MAPPER.lock();
+        let mut frame_allocator = FRAME_ALLOCATOR.lock();
+        f(
+            mapper.as_mut().expect("memory uninitialized"),
+            frame_allocator.as_mut().expect("memory uninitialized"),
+        )
+    })
+}
+
+/// Runs `f` with the frame allocator.
+pub(crate) fn with_frame_allocator<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut BitmapFrameAllocator) -> R,
+{
+    interrupts::without_interrupts(|| {
+        let mut frame_allocator = FRAME_ALLOCATOR.lock();
+        f(frame_allocator.as_mut().expect("memory uninitialized"))
+    })
+}
+
+fn with_buddy_allocator<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut BuddyAllocator) -> R,
+{
+    interrupts::without_interrupts(|| {
+        let mut buddy_allocator = BUDDY_ALLOCATOR.lock();
+        f(buddy_allocator.as_mut().expect("memory uninitialized"))
+    })
+}
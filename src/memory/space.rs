@@ -0,0 +1,273 @@
+//! Per-process address spaces
+//!
+//! Each address space has its own level 4 page table, which shares all the
+//! kernel level 4 entries with the kernel page table, and maps the user
+//! pages in the user range, which the kernel keeps unused.
+//!
+//! The user range is not the lower half, as the bootloader puts the kernel
+//! image, the boot stack and the physical memory window there, but the
+//! fixed level 4 entries between those and the VMM window.
+use super::{user, vmm, BitmapFrameAllocator};
+use conquer_once::spin::OnceCell;
+use core::ops::Range;
+use x86_64::{
+    registers::control::Cr3,
+    structures::paging::{
+        mapper::{MapToError, UnmapError},
+        page_table::PageTableEntry,
+        FrameAllocator, FrameDeallocator, Mapper, MapperAllSizes, OffsetPageTable, Page, PageTable,
+        PageTableFlags, PageTableIndex, PhysFrame, Size4KiB, UnusedPhysFrame,
+    },
+    PhysAddr, VirtAddr,
+};
+
+/// User range start address.
+pub const USER_START: u64 = 0x_2000_0000_0000;
+/// User range end address.
+pub const USER_END: u64 = 0x_4000_0000_0000;
+
+static KERNEL_LEVEL_4_FRAME: OnceCell<PhysFrame> = OnceCell::uninit();
+
+pub(super) fn init() {
+    let (frame, _) = Cr3::read();
+    KERNEL_LEVEL_4_FRAME
+        .try_init_once(|| frame)
+        .expect("address space should only be initialized once");
+}
+
+/// Allocates the empty level 3 tables for the VMM window, as the address
+/// spaces copy the kernel level 4 entries only once, and never see the ones
+/// added later.
+pub(super) fn init_kernel_tables(
+    level_4_table: &mut PageTable,
+    frame_allocator: &mut BitmapFrameAllocator,
+) -> Result<(), MapToError<Size4KiB>> {
+    let start = usize::from(VirtAddr::new(vmm::VMM_START).p4_index());
+    let end = usize::from(VirtAddr::new(vmm::VMM_END).p4_index());
+    for entry in level_4_table.iter_mut().take(end).skip(start) {
+        if !entry.is_unused() {
+            continue;
+        }
+        let frame = frame_allocator
+            .allocate_frame()
+            .ok_or(MapToError::FrameAllocationFailed)?;
+        unsafe { *table(*frame) = PageTable::new() };
+        entry.set_frame(*frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+    }
+    Ok(())
+}
+
+/// Process address space.
+#[derive(Debug)]
+pub struct AddressSpace {
+    level_4_frame: PhysFrame,
+}
+
+impl AddressSpace {
+    /// Creates an address space with the kernel mappings.
+    pub fn new() -> Result<Self, MapToError<Size4KiB>> {
+        let frame = super::with_frame_allocator(|frame_allocator| {
+            frame_allocator
+                .allocate_frame()
+                .ok_or(MapToError::FrameAllocationFailed)
+        })?;
+        let space = Self {
+            level_4_frame: *frame,
+        };
+        let kernel = unsafe { table(kernel_level_4_frame()) };
+        let table = unsafe { table(space.level_4_frame) };
+        for (i, entry) in kernel.iter().enumerate() {
+            if user_indexes().contains(&i) {
+                assert!(entry.is_unused(), "kernel mapping in the user range");
+                table[i].set_unused();
+            } else {
+                table[i] = entry.clone();
+            }
+        }
+        Ok(space)
+    }
+    /// Maps the user `page` to a newly allocated frame with `flags`.
+    pub fn map_user(
+        &mut self,
+        page: Page<Size4KiB>,
+        flags: PageTableFlags,
+    ) -> Result<(), MapToError<Size4KiB>> {
+        assert!(is_user(page.start_address()), "not in the user range");
+        let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        let mut mapper = unsafe { self.mapper() };
+        super::with_frame_allocator(|frame_allocator| {
+            let frame = frame_allocator
+                .allocate_frame()
+                .ok_or(MapToError::FrameAllocationFailed)?;
+            mapper.map_to(page, frame, flags, frame_allocator)?.flush();
+            unsafe { user::set_parents_user_accessible(&mut mapper, page) };
+            Ok(())
+        })
+    }
+    /// Unmaps the user `page` and returns the backing frame to the frame
+    /// allocator.
+    ///
+    /// # Safety
+    ///
+    /// The caller must guarantee that nothing references the page.
+    pub unsafe fn unmap_user(&mut self, page: Page<Size4KiB>) -> Result<(), UnmapError> {
+        assert!(is_user(page.start_address()), "not in the user range");
+        let mut mapper = self.mapper();
+        let (frame, flush) = mapper.unmap(page)?;
+        flush.flush();
+        super::with_frame_allocator(|frame_allocator| {
+            frame_allocator.deallocate_frame(UnusedPhysFrame::new(frame));
+        });
+        Ok(())
+    }
+    /// Translates `addr` in the address space.
+    pub fn translate_addr(&self, addr: VirtAddr) -> Option<PhysAddr> {
+        unsafe { self.mapper() }.translate_addr(addr)
+    }
+    /// Returns the mapper of the address space, e.g. for the copy-on-write
+    /// mappings.
+    ///
+    /// # Safety
+    ///
+    /// The caller must guarantee that the kernel mappings are not modified
+    /// through it.
+    pub unsafe fn mapper(&self) -> OffsetPageTable<'_> {
+        OffsetPageTable::new(table(self.level_4_frame), super::physical_memory_offset())
+    }
+    /// Returns true when the address space is active.
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0 == self.level_4_frame
+    }
+    /// Switches to the address space.
+    ///
+    /// # Safety
+    ///
+    /// The caller must guarantee that the address space is not dropped while
+    /// it's active.
+    pub unsafe fn activate(&self) {
+        let (_, flags) = Cr3::read();
+        Cr3::write(self.level_4_frame, flags);
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        assert!(!self.is_active(), "drop of the active address space");
+        let table = unsafe { table(self.level_4_frame) };
+        super::with_frame_allocator(|frame_allocator| unsafe {
+            for i in user_indexes() {
+                free_table(&mut table[i], 3, frame_allocator);
+            }
+            frame_allocator.deallocate_frame(UnusedPhysFrame::new(self.level_4_frame));
+        });
+    }
+}
+
+/// Switches back to the kernel page table.
+///
+/// # Safety
+///
+/// The caller must guarantee that nothing references the user pages.
+pub unsafe fn activate_kernel() {
+    let (_, flags) = Cr3::read();
+    Cr3::write(kernel_level_4_frame(), flags);
+}
+
+/// Returns true when `addr` is in the user range.
+pub fn is_user(addr: VirtAddr) -> bool {
+    USER_START <= addr.as_u64() && addr.as_u64() < USER_END
+}
+
+fn user_indexes() -> Range<usize> {
+    let start = VirtAddr::new(USER_START).p4_index();
+    let end = VirtAddr::new(USER_END).p4_index();
+    usize::from(start)..usize::from(end)
+}
+
+fn kernel_level_4_frame() -> PhysFrame {
+    *KERNEL_LEVEL_4_FRAME
+        .try_get()
+        .expect("memory uninitialized")
+}
+
+unsafe fn table<'a>(frame: PhysFrame) -> &'a mut PageTable {
+    let virt = super::physical_memory_offset() + frame.start_address().as_u64();
+    &mut *virt.as_mut_ptr()
+}
+
+// frees the user page table of the `level` pointed by `entry`, with all
+// the frames mapped by it.
+unsafe fn free_table(
+    entry: &mut PageTableEntry,
+    level: usize,
+    frame_allocator: &mut BitmapFrameAllocator,
+) {
+    let frame = match entry.frame() {
+        Ok(frame) => frame,
+        Err(_) => return,
+    };
+    if level > 0 {
+        let table = table(frame);
+        for i in 0..512 {
+            free_table(
+                &mut table[PageTableIndex::new(i)],
+                level - 1,
+                frame_allocator,
+            );
+        }
+    }
+    frame_allocator.deallocate_frame(UnusedPhysFrame::new(frame));
+    entry.set_unused();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{super::vmm, AddressSpace};
+    use crate::{serial_print, serial_println};
+    use x86_64::{
+        structures::paging::{MapperAllSizes, Page, PageTableFlags},
+        VirtAddr,
+    };
+    #[test_case]
+    fn user_mapping() {
+        serial_print!("memory::space::user_mapping... ");
+        let addr = VirtAddr::new(super::USER_START + 0x1000);
+        let page = Page::containing_address(addr);
+        let free = super::super::free_frames();
+        let mut space = AddressSpace::new().expect("no address space");
+        space
+            .map_user(page, PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE)
+            .expect("map failed");
+        assert!(space.translate_addr(addr).is_some());
+        // kernel code is shared, but not the user page.
+        let code = VirtAddr::new(user_mapping as *const () as u64);
+        let (kernel_code, kernel_user) = super::super::with_mapper(|mapper, _| {
+            (mapper.translate_addr(code), mapper.translate_addr(addr))
+        });
+        assert_eq!(space.translate_addr(code), kernel_code);
+        assert!(kernel_user.is_none());
+        let mut buf = [0u8; 5];
+        unsafe {
+            space.activate();
+            super::super::user_copy_to(addr.as_mut_ptr(), b"hello");
+            super::super::user_copy_from(&mut buf, addr.as_ptr());
+            super::activate_kernel();
+        }
+        assert_eq!(&buf, b"hello");
+        drop(space);
+        assert_eq!(super::super::free_frames(), free);
+        serial_println!("[ok]");
+    }
+    #[test_case]
+    fn later_kernel_mapping() {
+        serial_print!("memory::space::later_kernel_mapping... ");
+        let space = AddressSpace::new().expect("no address space");
+        let flags = PageTableFlags::WRITABLE;
+        let region =
+            vmm::allocate("late", vmm::RegionKind::Anonymous, 1, flags).expect("no region");
+        assert!(space.translate_addr(region.start).is_some());
+        unsafe { vmm::release(region.start).expect("release failed") };
+        drop(space);
+        serial_println!("[ok]");
+    }
+}
@@ -0,0 +1,26 @@
+//! Kernel stacks with the guard pages
+use super::vmm::{self, RegionKind, VmmError};
+use x86_64::{structures::paging::PageTableFlags, VirtAddr};
+
+/// Kernel stack.
+#[derive(Debug, Clone, Copy)]
+pub struct Stack {
+    /// The stack name.
+    pub name: &'static str,
+    /// The lowest address of the stack.
+    pub bottom: VirtAddr,
+    /// The highest address of the stack, e.g. the initial stack pointer.
+    pub top: VirtAddr,
+}
+
+/// Allocates `pages` pages of the kernel stack, with an unmapped guard page
+/// beneath it.
+pub fn alloc_stack(name: &'static str, pages: u64) -> Result<Stack, VmmError> {
+    let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let region = vmm::allocate(name, RegionKind::Stack, pages, flags)?;
+    Ok(Stack {
+        name,
+        bottom: region.start,
+        top: region.end(),
+    })
+}
@@ -0,0 +1,105 @@
+//! Supervisor mode access and execution prevention
+//!
+//! Once enabled, the kernel faults on any user accessible page, except
+//! inside the `user_copy_from` and `user_copy_to` helpers, which open the
+//! window with `stac` and `clac`.
+use core::{
+    arch::x86_64::__cpuid_count,
+    ptr,
+    sync::atomic::{AtomicBool, Ordering},
+};
+use x86_64::{
+    instructions::{interrupts, tlb},
+    registers::control::{Cr4, Cr4Flags},
+    structures::paging::{OffsetPageTable, Page, PageTable, PageTableFlags, Size4KiB},
+};
+
+static SMAP_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Returns true when the CPU supports the supervisor mode execution
+/// prevention.
+pub fn has_smep() -> bool {
+    // CPUID.(EAX=07H,ECX=0H):EBX.SMEP[bit 7]
+    structured_features() & (1 << 7) != 0
+}
+
+/// Returns true when the CPU supports the supervisor mode access
+/// prevention.
+pub fn has_smap() -> bool {
+    // CPUID.(EAX=07H,ECX=0H):EBX.SMAP[bit 20]
+    structured_features() & (1 << 20) != 0
+}
+
+fn structured_features() -> u32 {
+    let max_leaf = unsafe { __cpuid_count(0, 0) }.eax;
+    if max_leaf < 7 {
+        return 0;
+    }
+    unsafe { __cpuid_count(7, 0) }.ebx
+}
+
+/// Enables SMEP and SMAP, when the CPU supports those.
+pub(crate) fn init() {
+    let mut flags = Cr4Flags::empty();
+    if has_smep() {
+        flags |= Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION;
+    }
+    if has_smap() {
+        flags |= Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION;
+    }
+    unsafe { Cr4::update(|cr4| cr4.insert(flags)) };
+    SMAP_ENABLED.store(has_smap(), Ordering::SeqCst);
+}
+
+/// Copies `dst.len()` bytes from the user memory at `src`.
+///
+/// # Safety
+///
+/// The caller must guarantee that `src` points to the valid user memory.
+pub unsafe fn user_copy_from(dst: &mut [u8], src: *const u8) {
+    with_user_access(|| ptr::copy_nonoverlapping(src, dst.as_mut_ptr(), dst.len()))
+}
+
+/// Copies `src` to the user memory at `dst`.
+///
+/// # Safety
+///
+/// The caller must guarantee that `dst` points to the valid user memory.
+pub unsafe fn user_copy_to(dst: *mut u8, src: &[u8]) {
+    with_user_access(|| ptr::copy_nonoverlapping(src.as_ptr(), dst, src.len()))
+}
+
+// runs `f` with `RFLAGS.AC` set, so that SMAP lets the kernel access the
+// user accessible pages.  Interrupts are disabled, as the interrupt entry
+// doesn't clear the flag.
+fn with_user_access<F: FnOnce()>(f: F) {
+    if !SMAP_ENABLED.load(Ordering::SeqCst) {
+        f();
+        return;
+    }
+    interrupts::without_interrupts(|| {
+        unsafe { asm!("stac") };
+        f();
+        unsafe { asm!("clac") };
+    })
+}
+
+/// Sets the `USER_ACCESSIBLE` flag on the parent table entries of the 4KiB
+/// `page`, as the CPU checks it on all the levels.
+///
+/// # Safety
+///
+/// The caller must guarantee that the `page` is mapped by `mapper`.
+pub(super) unsafe fn set_parents_user_accessible(
+    mapper: &mut OffsetPageTable,
+    page: Page<Size4KiB>,
+) {
+    let offset = super::physical_memory_offset();
+    let mut table: *mut PageTable = mapper.level_4_table();
+    for &index in &[page.p4_index(), page.p3_index(), page.p2_index()] {
+        let entry = &mut (*table)[index];
+        entry.set_flags(entry.flags() | PageTableFlags::USER_ACCESSIBLE);
+        table = (offset + entry.addr().as_u64()).as_mut_ptr();
+    }
+    tlb::flush(page.start_address());
+}
@@ -0,0 +1,556 @@
+//! Kernel virtual address space manager
+//!
+//! It keeps track of the kernel virtual memory regions, e.g. the heap, the
+//! stacks and the MMIO, in the VMM window and hands out the non-overlapping
+//! ones.  Each heap and stack region has the unmapped guard pages around it.
+use super::guard::{self, GuardKind};
+#[cfg(feature = "kaslr")]
+use super::kaslr::region_base;
+use super::Zone;
+use spin::Mutex;
+use x86_64::{
+    instructions::interrupts,
+    structures::paging::{
+        mapper::{MapToError, UnmapError},
+        Page, PageSize, PageTableFlags, PhysFrame, Size1GiB, Size2MiB, Size4KiB,
+    },
+    PhysAddr, VirtAddr,
+};
+
+/// VMM window start address.
+pub const VMM_START: u64 = 0x_4000_0000_0000;
+/// VMM window end address.
+pub const VMM_END: u64 = 0x_6000_0000_0000;
+
+/// The maximum number of the regions.
+pub(super) const MAX_REGIONS: usize = 64;
+const PAGE_SIZE: u64 = Size4KiB::SIZE;
+
+static REGIONS: Mutex<[Option<Region>; MAX_REGIONS]> = Mutex::new([None; MAX_REGIONS]);
+
+/// The number of the region kinds.
+pub(super) const REGION_KINDS: usize = 5;
+
+/// Kernel virtual memory region kinds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionKind {
+    /// Kernel heap, with the guard pages on both sides.
+    Heap,
+    /// Kernel stack, with the guard page beneath it.
+    Stack,
+    /// Memory mapped device registers.
+    Mmio,
+    /// Frame buffer.
+    Framebuffer,
+    /// General purpose memory.
+    Anonymous,
+}
+
+impl RegionKind {
+    pub(super) fn index(self) -> usize {
+        self as usize
+    }
+    /// Returns the number of the guard pages below and above the region.
+    fn guard_pages(self) -> (u64, u64) {
+        match self {
+            Self::Heap => (1, 1),
+            Self::Stack => (1, 0),
+            _ => (0, 0),
+        }
+    }
+    /// Returns true when the region owns the backing frames, which is
+    /// returned to the frame allocator on release.
+    fn owns_frames(self) -> bool {
+        match self {
+            Self::Mmio | Self::Framebuffer => false,
+            _ => true,
+        }
+    }
+}
+
+/// VMM errors.
+#[derive(Debug)]
+pub enum VmmError {
+    /// No free virtual address range large enough.
+    OutOfVirtualMemory,
+    /// The range overlaps with the existing region.
+    Overlap,
+    /// No room to track the region.
+    TooManyRegions,
+    /// No region starts at the address.
+    NotFound(VirtAddr),
+    /// Page mapping failed.
+    Map(MapToError<Size4KiB>),
+    /// Page unmapping failed.
+    Unmap(UnmapError),
+    /// The region is user accessible, which belongs to the user range of
+    /// the `AddressSpace` instead.
+    UserAccessible,
+    /// The region kind owns its frames, so it can't map the foreign ones.
+    OwnsFrames(RegionKind),
+}
+
+impl From<MapToError<Size4KiB>> for VmmError {
+    fn from(err: MapToError<Size4KiB>) -> Self {
+        Self::Map(err)
+    }
+}
+
+impl From<UnmapError> for VmmError {
+    fn from(err: UnmapError) -> Self {
+        Self::Unmap(err)
+    }
+}
+
+/// Kernel virtual memory region.
+#[derive(Debug, Clone, Copy)]
+pub struct Region {
+    /// The region name.
+    pub name: &'static str,
+    /// The region kind.
+    pub kind: RegionKind,
+    /// The first usable address of the region.
+    pub start: VirtAddr,
+    /// The number of usable pages.
+    pub pages: u64,
+    /// The page table flags for the region.
+    pub flags: PageTableFlags,
+    /// True when the pages are allocated on the first touch.
+    pub lazy: bool,
+}
+
+impl Region {
+    /// Returns the end address of the region.
+    pub fn end(&self) -> VirtAddr {
+        self.start + self.pages * PAGE_SIZE
+    }
+    /// Returns the usable region size in bytes.
+    pub fn size(&self) -> u64 {
+        self.pages * PAGE_SIZE
+    }
+    /// Returns true when `addr` is in the usable part of the region.
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        self.start <= addr && addr < self.end()
+    }
+    /// Returns the usable pages of the region.
+    pub fn page_range(&self) -> impl Iterator<Item = Page<Size4KiB>> {
+        let start = Page::containing_address(self.start);
+        (0..self.pages).map(move |i| start + i)
+    }
+    // returns the virtual address range including the guard pages.
+    fn span(&self) -> (u64, u64) {
+        let (below, above) = self.kind.guard_pages();
+        let start = self.start.as_u64() - below * PAGE_SIZE;
+        let end = self.end().as_u64() + above * PAGE_SIZE;
+        (start, end)
+    }
+    fn guards(&self) -> impl Iterator<Item = Page<Size4KiB>> {
+        let (below, above) = self.kind.guard_pages();
+        let start = Page::containing_address(self.start);
+        let end = Page::containing_address(self.end());
+        (1..=below)
+            .map(move |i| start - i)
+            .chain((0..above).map(move |i| end + i))
+    }
+}
+
+/// Reserves `pages` pages of the virtual address range at `start`, without
+/// mapping those.
+pub fn reserve_at(
+    name: &'static str,
+    kind: RegionKind,
+    start: VirtAddr,
+    pages: u64,
+    flags: PageTableFlags,
+) -> Result<Region, VmmError> {
+    let region = Region {
+        name,
+        kind,
+        start: start.align_down(PAGE_SIZE),
+        pages,
+        flags: flags | PageTableFlags::PRESENT,
+        lazy: false,
+    };
+    interrupts::without_interrupts(|| {
+        let mut regions = REGIONS.lock();
+        let (start, end) = region.span();
+        if overlaps(&regions, start, end) {
+            return Err(VmmError::Overlap);
+        }
+        insert(&mut regions, region)
+    })
+}
+
+/// Reserves `pages` pages of the free virtual address range in the VMM
+/// window, without mapping those.
+pub fn reserve(
+    name: &'static str,
+    kind: RegionKind,
+    pages: u64,
+    flags: PageTableFlags,
+) -> Result<Region, VmmError> {
+    reserve_region(name, kind, pages, flags, PAGE_SIZE, false)
+}
+
+/// Reserves `pages` pages of the free virtual address range in the VMM
+/// window aligned for the huge pages, without mapping those.
+///
+/// It's aligned to 1GiB when the region is large enough and the CPU
+/// supports the 1GiB pages, or to 2MiB otherwise.
+pub fn reserve_huge(
+    name: &'static str,
+    kind: RegionKind,
+    pages: u64,
+    flags: PageTableFlags,
+) -> Result<Region, VmmError> {
+    let align = if pages * PAGE_SIZE >= Size1GiB::SIZE && super::has_1gib_pages() {
+        Size1GiB::SIZE
+    } else {
+        Size2MiB::SIZE
+    };
+    reserve_region(name, kind, pages, flags, align, false)
+}
+
+/// Reserves `pages` pages of the free virtual address range in the VMM
+/// window, which will be backed by the zeroed frames on the first touch.
+pub fn reserve_lazy(
+    name: &'static str,
+    kind: RegionKind,
+    pages: u64,
+    flags: PageTableFlags,
+) -> Result<Region, VmmError> {
+    reserve_region(name, kind, pages, flags, PAGE_SIZE, true)
+}
+
+fn reserve_region(
+    name: &'static str,
+    kind: RegionKind,
+    pages: u64,
+    flags: PageTableFlags,
+    align: u64,
+    lazy: bool,
+) -> Result<Region, VmmError> {
+    let (below, above) = kind.guard_pages();
+    let size = pages * PAGE_SIZE;
+    interrupts::without_interrupts(|| {
+        let mut regions = REGIONS.lock();
+        let start = find_gap(&regions, kind, below, size, above, align)
+            .ok_or(VmmError::OutOfVirtualMemory)?;
+        let region = Region {
+            name,
+            kind,
+            start: VirtAddr::new(start),
+            pages,
+            flags: flags | PageTableFlags::PRESENT,
+            lazy,
+        };
+        insert(&mut regions, region)
+    })
+}
+
+/// Allocates `pages` pages of the virtual memory region backed by the newly
+/// allocated frames.
+pub fn allocate(
+    name: &'static str,
+    kind: RegionKind,
+    pages: u64,
+    flags: PageTableFlags,
+) -> Result<Region, VmmError> {
+    let region = reserve(name, kind, pages, flags)?;
+    for page in region.page_range() {
+        if let Err(err) = super::map_page(page, region.flags) {
+            unsafe { release(region.start).expect("release failed") };
+            return Err(err.into());
+        }
+    }
+    Ok(region)
+}
+
+/// Allocates `pages` pages, rounded up to 2MiB, of the virtual memory region
+/// backed by the 2MiB frames from the buddy allocator.
+///
+/// It falls back to the 4KiB frames when no 2MiB frame is available.
+pub fn allocate_huge(
+    name: &'static str,
+    kind: RegionKind,
+    pages: u64,
+    flags: PageTableFlags,
+) -> Result<Region, VmmError> {
+    let huge_pages = Size2MiB::SIZE / PAGE_SIZE;
+    let pages = (pages + huge_pages - 1) / huge_pages * huge_pages;
+    let region = reserve_region(name, kind, pages, flags, Size2MiB::SIZE, false)?;
+    let mut addr = region.start;
+    while addr < region.end() {
+        if let Some(block) = super::allocate_contiguous(super::HUGE_PAGE_ORDER, Zone::Normal) {
+            let page = Page::<Size2MiB>::containing_address(addr);
+            let frame = PhysFrame::from_start_address(block.start_address())
+                .expect("misaligned huge frame");
+            if unsafe { super::map_frame(page, frame, region.flags) }.is_ok() {
+                addr += Size2MiB::SIZE;
+                continue;
+            }
+            unsafe { super::deallocate_contiguous(block, super::HUGE_PAGE_ORDER) };
+        }
+        let start = Page::<Size4KiB>::containing_address(addr);
+        for page in (0..huge_pages).map(|i| start + i) {
+            if let Err(err) = super::map_page(page, region.flags) {
+                unsafe { release(region.start).expect("release failed") };
+                return Err(err.into());
+            }
+        }
+        addr += Size2MiB::SIZE;
+    }
+    Ok(region)
+}
+
+/// Maps the reserved `region` to the physical memory starting at `phys`.
+///
+/// It uses the huge pages where both the virtual and the physical addresses
+/// are aligned, e.g. the region reserved by `reserve_huge`, and falls back
+/// to the 4KiB pages otherwise.
+///
+/// Only the regions not owning the frames, e.g. `RegionKind::Mmio`, can
+/// be mapped, as `release` returns the owned frames to the allocators.
+///
+/// # Safety
+///
+/// The caller must guarantee that the physical memory is not used by
+/// anything else, e.g. the device memory.
+///
+/// Nothing is left mapped on error.
+pub unsafe fn map_phys(region: &Region, phys: PhysAddr) -> Result<(), VmmError> {
+    if region.kind.owns_frames() {
+        return Err(VmmError::OwnsFrames(region.kind));
+    }
+    map_phys_pages(region, phys).map_err(|(mapped, err)| {
+        // the device memory is not owned, so just unmap it.
+        let end = region.start + mapped;
+        match super::unmap_range(region.start, end, false) {
+            Ok(()) => err,
+            Err(err) => err.into(),
+        }
+    })
+}
+
+// maps the region, and returns the mapped size on error.
+unsafe fn map_phys_pages(region: &Region, phys: PhysAddr) -> Result<(), (u64, VmmError)> {
+    let base = phys.align_down(PAGE_SIZE);
+    let mut offset = 0;
+    while offset < region.size() {
+        let (virt, phys, left) = (region.start + offset, base + offset, region.size() - offset);
+        let aligned = |size: u64| virt.is_aligned(size) && phys.is_aligned(size) && left >= size;
+        if aligned(Size1GiB::SIZE) && super::has_1gib_pages() {
+            let page = Page::<Size1GiB>::containing_address(virt);
+            let frame = PhysFrame::containing_address(phys);
+            if super::map_frame(page, frame, region.flags).is_ok() {
+                offset += Size1GiB::SIZE;
+                continue;
+            }
+        }
+        if aligned(Size2MiB::SIZE) {
+            let page = Page::<Size2MiB>::containing_address(virt);
+            let frame = PhysFrame::containing_address(phys);
+            if super::map_frame(page, frame, region.flags).is_ok() {
+                offset += Size2MiB::SIZE;
+                continue;
+            }
+        }
+        let page = Page::<Size4KiB>::containing_address(virt);
+        super::map_frame(page, PhysFrame::containing_address(phys), region.flags)
+            .map_err(|err| (offset, err.into()))?;
+        offset += PAGE_SIZE;
+    }
+    Ok(())
+}
+
+/// Releases the region starting at `start`, unmapping its pages and
+/// returning the owned frames to the frame allocator.
+///
+/// The region stays reserved when the unmapping fails.
+///
+/// # Safety
+///
+/// The caller must guarantee that nothing references the region.
+pub unsafe fn release(start: VirtAddr) -> Result<(), VmmError> {
+    let region = interrupts::without_interrupts(|| {
+        let mut regions = REGIONS.lock();
+        let slot = regions
+            .iter_mut()
+            .find(|r| r.map_or(false, |r| r.start == start))
+            .ok_or(VmmError::NotFound(start))?;
+        Ok(slot.take().unwrap())
+    })?;
+    if let Err(err) = super::unmap_range(region.start, region.end(), region.kind.owns_frames()) {
+        interrupts::without_interrupts(|| {
+            let mut regions = REGIONS.lock();
+            let slot = regions.iter_mut().find(|r| r.is_none());
+            *slot.expect("region slot taken") = Some(region);
+        });
+        return Err(err.into());
+    }
+    for page in region.guards() {
+        guard::unregister(page);
+    }
+    Ok(())
+}
+
+/// Returns the region containing `addr`, if any.
+///
+/// It's called by the exception handlers, so it gives up instead of
+/// spinning on the lock.
+pub fn find(addr: VirtAddr) -> Option<Region> {
+    let regions = REGIONS.try_lock()?;
+    regions.iter().flatten().find(|r| r.contains(addr)).copied()
+}
+
+fn overlaps(regions: &[Option<Region>], start: u64, end: u64) -> bool {
+    regions.iter().flatten().any(|r| {
+        let (s, e) = r.span();
+        start < e && s < end
+    })
+}
+
+// returns the first usable address of the free range in the VMM window,
+// which is aligned to `align` and has the guard pages around it.
+//
+// It starts from the `kind` base picked at boot with the `kaslr` feature,
+// so that the regions of the same kind are packed above it.
+fn find_gap(
+    regions: &[Option<Region>],
+    kind: RegionKind,
+    below: u64,
+    size: u64,
+    above: u64,
+    align: u64,
+) -> Option<u64> {
+    find_gap_from(region_base(kind), regions, below, size, above, align)
+        .or_else(|| find_gap_from(VMM_START, regions, below, size, above, align))
+}
+
+// returns the base address of the `kind` regions.
+#[cfg(not(feature = "kaslr"))]
+fn region_base(_kind: RegionKind) -> u64 {
+    VMM_START
+}
+
+fn find_gap_from(
+    mut start: u64,
+    regions: &[Option<Region>],
+    below: u64,
+    size: u64,
+    above: u64,
+    align: u64,
+) -> Option<u64> {
+    loop {
+        let usable = align_up(start + below * PAGE_SIZE, align);
+        let (span_start, span_end) = (
+            usable - below * PAGE_SIZE,
+            usable + size + above * PAGE_SIZE,
+        );
+        if span_end > VMM_END {
+            return None;
+        }
+        let next = regions
+            .iter()
+            .flatten()
+            .map(|r| r.span())
+            .filter(|&(s, e)| span_start < e && s < span_end)
+            .map(|(_, e)| e)
+            .max();
+        match next {
+            Some(next) => start = next,
+            None => return Some(usable),
+        }
+    }
+}
+
+fn align_up(addr: u64, align: u64) -> u64 {
+    (addr + align - 1) & !(align - 1)
+}
+
+fn insert(regions: &mut [Option<Region>], region: Region) -> Result<Region, VmmError> {
+    if region.flags.contains(PageTableFlags::USER_ACCESSIBLE) {
+        return Err(VmmError::UserAccessible);
+    }
+    let slot = regions
+        .iter_mut()
+        .find(|r| r.is_none())
+        .ok_or(VmmError::TooManyRegions)?;
+    let kind = match region.kind {
+        RegionKind::Heap => GuardKind::Heap,
+        _ => GuardKind::Stack,
+    };
+    // registers the guards first, so that the failure leaves no trace.
+    for page in region.guards() {
+        if let Err(err) = guard::register(page, kind, region.name) {
+            region.guards().for_each(guard::unregister);
+            return Err(err);
+        }
+    }
+    *slot = Some(region);
+    Ok(region)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RegionKind, VmmError};
+    use crate::{serial_print, serial_println};
+    use x86_64::{structures::paging::PageTableFlags, PhysAddr};
+    #[test_case]
+    fn allocate_and_release() {
+        serial_print!("memory::vmm::allocate_and_release... ");
+        let flags = PageTableFlags::WRITABLE;
+        let region = super::allocate("test", RegionKind::Anonymous, 4, flags).expect("no region");
+        let ptr = (region.end() - 8u64).as_mut_ptr::<u64>();
+        unsafe { ptr.write_volatile(42) };
+        assert_eq!(unsafe { ptr.read_volatile() }, 42);
+        let other = super::allocate("test", RegionKind::Anonymous, 1, flags).expect("no region");
+        assert!(other.start >= region.end() || other.end() <= region.start);
+        let free = super::super::free_frames();
+        unsafe {
+            super::release(other.start).expect("release failed");
+            super::release(region.start).expect("release failed");
+        }
+        assert!(super::find(region.start).is_none());
+        assert_eq!(super::super::free_frames(), free + 5);
+        serial_println!("[ok]");
+    }
+    #[test_case]
+    fn map_phys_owned() {
+        serial_print!("memory::vmm::map_phys_owned... ");
+        let flags = PageTableFlags::WRITABLE;
+        let region = super::reserve("test", RegionKind::Anonymous, 1, flags).expect("no region");
+        match unsafe { super::map_phys(&region, PhysAddr::new(0xb8000)) } {
+            Err(VmmError::OwnsFrames(RegionKind::Anonymous)) => {}
+            result => panic!("unexpected result: {:?}", result),
+        }
+        unsafe { super::release(region.start).expect("release failed") };
+        serial_println!("[ok]");
+    }
+    #[test_case]
+    fn too_many_regions() {
+        serial_print!("memory::vmm::too_many_regions... ");
+        let flags = PageTableFlags::WRITABLE;
+        let mut stacks = [None; super::MAX_REGIONS];
+        let mut err = None;
+        for slot in stacks.iter_mut() {
+            match super::reserve("test stack", RegionKind::Stack, 1, flags) {
+                Ok(region) => *slot = Some(region.start),
+                Err(e) => {
+                    err = Some(e);
+                    break;
+                }
+            }
+        }
+        match err {
+            Some(VmmError::TooManyRegions) => {}
+            err => panic!("unexpected result: {:?}", err),
+        }
+        let count = stacks.iter().flatten().count();
+        assert!(count > 32, "only {} stacks", count);
+        for &start in stacks.iter().flatten() {
+            unsafe { super::release(start).expect("release failed") };
+        }
+        serial_println!("[ok]");
+    }
+}
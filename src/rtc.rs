@@ -0,0 +1,446 @@
+//! CMOS real-time clock and the wall-clock time
+//!
+//! The RTC is read once at boot, with the update-in-progress flag and the
+//! BCD and 12 hour modes handled, and `SystemTime` adds the monotonic clock
+//! to it from there.  The RTC IRQ 8 provides the periodic and the alarm
+//! interrupts on demand.
+use core::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    task::{Context, Poll},
+    time::Duration,
+};
+use futures_util::task::AtomicWaker;
+use spin::Mutex;
+use x86_64::instructions::{interrupts, port::Port};
+
+const CMOS_INDEX: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+
+// CMOS registers.
+const SECONDS: u8 = 0x00;
+const SECONDS_ALARM: u8 = 0x01;
+const MINUTES: u8 = 0x02;
+const MINUTES_ALARM: u8 = 0x03;
+const HOURS: u8 = 0x04;
+const HOURS_ALARM: u8 = 0x05;
+const DAY: u8 = 0x07;
+const MONTH: u8 = 0x08;
+const YEAR: u8 = 0x09;
+const STATUS_A: u8 = 0x0a;
+const STATUS_B: u8 = 0x0b;
+const STATUS_C: u8 = 0x0c;
+const CENTURY: u8 = 0x32;
+
+// status register bits.
+const UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const RATE_MASK: u8 = 0x0f;
+const HOUR_24: u8 = 1 << 1;
+const BINARY: u8 = 1 << 2;
+const ALARM_INTERRUPT: u8 = 1 << 5;
+const PERIODIC_INTERRUPT: u8 = 1 << 6;
+const HOUR_PM: u8 = 1 << 7;
+
+// the update cycle takes about 2ms, which is far less than this many
+// status reads through the slow CMOS ports.
+const UPDATE_SPIN_LIMIT: usize = 100_000;
+
+/// RTC IRQ number.
+pub(crate) const RTC_IRQ: u8 = 8;
+
+static CMOS: Mutex<Cmos> = Mutex::new(Cmos::new());
+// wall-clock time of the clock source zero, in nanoseconds.
+static BOOT_TIME: AtomicU64 = AtomicU64::new(0);
+static PERIODIC_TICKS: AtomicU64 = AtomicU64::new(0);
+static ALARM_FIRED: AtomicBool = AtomicBool::new(false);
+static ALARM_WAKER: AtomicWaker = AtomicWaker::new();
+
+struct Cmos {
+    index: Port<u8>,
+    data: Port<u8>,
+}
+
+impl Cmos {
+    const fn new() -> Self {
+        Self {
+            index: Port::new(CMOS_INDEX),
+            data: Port::new(CMOS_DATA),
+        }
+    }
+    fn read(&mut self, reg: u8) -> u8 {
+        unsafe {
+            self.index.write(reg);
+            self.data.read()
+        }
+    }
+    fn write(&mut self, reg: u8, value: u8) {
+        unsafe {
+            self.index.write(reg);
+            self.data.write(value);
+        }
+    }
+    fn update(&mut self, reg: u8, f: impl FnOnce(u8) -> u8) {
+        let value = self.read(reg);
+        self.write(reg, f(value));
+    }
+    fn is_updating(&mut self) -> bool {
+        self.read(STATUS_A) & UPDATE_IN_PROGRESS != 0
+    }
+    // reads the raw time registers outside of the update cycle, or anyway
+    // when the update doesn't end, as the caller compares two reads.
+    fn read_raw(&mut self) -> [u8; 7] {
+        for _ in 0..UPDATE_SPIN_LIMIT {
+            if !self.is_updating() {
+                break;
+            }
+        }
+        [
+            self.read(SECONDS),
+            self.read(MINUTES),
+            self.read(HOURS),
+            self.read(DAY),
+            self.read(MONTH),
+            self.read(YEAR),
+            self.read(CENTURY),
+        ]
+    }
+    fn read_date_time(&mut self) -> DateTime {
+        // reads until two in a row agree, as the update may start anytime.
+        let mut raw = self.read_raw();
+        loop {
+            let again = self.read_raw();
+            if again == raw {
+                break;
+            }
+            raw = again;
+        }
+        let mode = self.read(STATUS_B);
+        let decode = |value| decode(value, mode);
+        let [second, minute, hour, day, month, year, century] = raw;
+        let year = u16::from(decode(year));
+        let century = match u16::from(decode(century)) {
+            century @ 19..=21 => century,
+            _ if year < 70 => 20,
+            _ => 19,
+        };
+        DateTime {
+            year: century * 100 + year,
+            month: decode(month),
+            day: decode(day),
+            hour: decode_hour(hour, mode),
+            minute: decode(minute),
+            second: decode(second),
+        }
+    }
+}
+
+fn decode(value: u8, mode: u8) -> u8 {
+    if mode & BINARY != 0 {
+        value
+    } else {
+        (value >> 4) * 10 + (value & 0x0f)
+    }
+}
+
+fn encode(value: u8, mode: u8) -> u8 {
+    if mode & BINARY != 0 {
+        value
+    } else {
+        ((value / 10) << 4) | (value % 10)
+    }
+}
+
+fn decode_hour(value: u8, mode: u8) -> u8 {
+    if mode & HOUR_24 != 0 {
+        return decode(value, mode);
+    }
+    // 12 AM is midnight and 12 PM is noon.
+    let hour = decode(value & !HOUR_PM, mode) % 12;
+    if value & HOUR_PM != 0 {
+        hour + 12
+    } else {
+        hour
+    }
+}
+
+fn encode_hour(hour: u8, mode: u8) -> u8 {
+    if mode & HOUR_24 != 0 {
+        return encode(hour, mode);
+    }
+    let twelve = match hour % 12 {
+        0 => 12,
+        hour => hour,
+    };
+    let pm = if hour >= 12 { HOUR_PM } else { 0 };
+    encode(twelve, mode) | pm
+}
+
+/// Calendar date and time in UTC, as kept by the RTC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DateTime {
+    /// The year, e.g. 2020.
+    pub year: u16,
+    /// The month, from 1 to 12.
+    pub month: u8,
+    /// The day of the month, from 1 to 31.
+    pub day: u8,
+    /// The hour, from 0 to 23.
+    pub hour: u8,
+    /// The minute, from 0 to 59.
+    pub minute: u8,
+    /// The second, from 0 to 59.
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Returns the seconds since the Unix epoch.
+    pub fn unix_timestamp(&self) -> u64 {
+        // days from the civil date, for the proleptic Gregorian calendar.
+        let (month, day) = (u64::from(self.month), u64::from(self.day));
+        let year = u64::from(self.year) - if month <= 2 { 1 } else { 0 };
+        let era = year / 400;
+        let year_of_era = year - era * 400;
+        let month_from_march = (month + 9) % 12;
+        let day_of_year = (153 * month_from_march + 2) / 5 + day - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = era * 146_097 + day_of_era - 719_468;
+        days * 86_400
+            + u64::from(self.hour) * 3600
+            + u64::from(self.minute) * 60
+            + u64::from(self.second)
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+            self.year, self.month, self.day, self.hour, self.minute, self.second,
+        )
+    }
+}
+
+/// Reads the boot time from the RTC.
+pub(crate) fn init() {
+    let date_time = now();
+    let nanos = date_time.unix_timestamp() * 1_000_000_000;
+    let since_boot = crate::time::clock_source().now_nanos();
+    BOOT_TIME.store(nanos - since_boot, Ordering::SeqCst);
+    crate::serial_println!("rtc: {}", date_time);
+}
+
+/// Reads the current date and time from the RTC.
+pub fn now() -> DateTime {
+    interrupts::without_interrupts(|| CMOS.lock().read_date_time())
+}
+
+/// Wall-clock time, measured from the Unix epoch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SystemTime(Duration);
+
+/// Error returned by `SystemTime::duration_since` for the later time, with
+/// the difference.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SystemTimeError(Duration);
+
+impl SystemTimeError {
+    /// Returns how much later the time was.
+    pub fn duration(&self) -> Duration {
+        self.0
+    }
+}
+
+impl fmt::Display for SystemTimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "second time provided was later than self")
+    }
+}
+
+impl SystemTime {
+    /// The Unix epoch, 1970-01-01T00:00:00Z.
+    pub const UNIX_EPOCH: SystemTime = SystemTime(Duration::from_secs(0));
+
+    /// Returns the current wall-clock time.
+    pub fn now() -> Self {
+        let since_boot = crate::time::clock_source().now_nanos();
+        Self(Duration::from_nanos(
+            BOOT_TIME.load(Ordering::SeqCst) + since_boot,
+        ))
+    }
+    /// Returns the time from `earlier` to this time.
+    pub fn duration_since(&self, earlier: SystemTime) -> Result<Duration, SystemTimeError> {
+        if self.0 >= earlier.0 {
+            Ok(self.0 - earlier.0)
+        } else {
+            Err(SystemTimeError(earlier.0 - self.0))
+        }
+    }
+    /// Returns the time elapsed since this time.
+    pub fn elapsed(&self) -> Result<Duration, SystemTimeError> {
+        Self::now().duration_since(*self)
+    }
+    /// Returns the time `duration` later, or `None` on overflow.
+    pub fn checked_add(&self, duration: Duration) -> Option<SystemTime> {
+        self.0.checked_add(duration).map(SystemTime)
+    }
+    /// Returns the time `duration` earlier, or `None` before the epoch.
+    pub fn checked_sub(&self, duration: Duration) -> Option<SystemTime> {
+        self.0.checked_sub(duration).map(SystemTime)
+    }
+}
+
+/// Enables the periodic interrupt at `32768 >> (rate - 1)` Hz, with `rate`
+/// from 3, 8192Hz, to 15, 2Hz.
+pub fn enable_periodic(rate: u8) {
+    assert!((3..=15).contains(&rate), "invalid rtc rate");
+    interrupts::without_interrupts(|| {
+        let mut cmos = CMOS.lock();
+        cmos.update(STATUS_A, |value| (value & !RATE_MASK) | rate);
+        enable_interrupt(&mut cmos, PERIODIC_INTERRUPT);
+    });
+}
+
+/// Disables the periodic interrupt.
+pub fn disable_periodic() {
+    interrupts::without_interrupts(|| {
+        CMOS.lock()
+            .update(STATUS_B, |value| value & !PERIODIC_INTERRUPT)
+    });
+}
+
+/// Returns the number of the periodic interrupts so far.
+pub fn periodic_ticks() -> u64 {
+    PERIODIC_TICKS.load(Ordering::SeqCst)
+}
+
+/// Sets the daily alarm at `hour`:`minute`:`second` in UTC, and returns the
+/// future which completes when it fires.
+pub fn set_alarm(hour: u8, minute: u8, second: u8) -> Alarm {
+    assert!(
+        hour < 24 && minute < 60 && second < 60,
+        "invalid alarm time"
+    );
+    ALARM_FIRED.store(false, Ordering::SeqCst);
+    interrupts::without_interrupts(|| {
+        let mut cmos = CMOS.lock();
+        let mode = cmos.read(STATUS_B);
+        cmos.write(SECONDS_ALARM, encode(second, mode));
+        cmos.write(MINUTES_ALARM, encode(minute, mode));
+        cmos.write(HOURS_ALARM, encode_hour(hour, mode));
+        enable_interrupt(&mut cmos, ALARM_INTERRUPT);
+    });
+    Alarm { _private: () }
+}
+
+/// Alarm future, returned by `set_alarm`.
+#[derive(Debug)]
+pub struct Alarm {
+    _private: (),
+}
+
+impl Future for Alarm {
+    type Output = ();
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if ALARM_FIRED.swap(false, Ordering::SeqCst) {
+            return Poll::Ready(());
+        }
+        ALARM_WAKER.register(cx.waker());
+        if ALARM_FIRED.swap(false, Ordering::SeqCst) {
+            ALARM_WAKER.take();
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+fn enable_interrupt(cmos: &mut Cmos, interrupt: u8) {
+    cmos.update(STATUS_B, |value| value | interrupt);
+    // The RTC holds the IRQ 8 until the status C is read.
+    cmos.read(STATUS_C);
+    crate::interrupts::enable_irq(RTC_IRQ);
+}
+
+/// Acknowledges the RTC interrupt, called by the IRQ 8 handler.
+pub(crate) fn handle_interrupt() {
+    let status = CMOS.lock().read(STATUS_C);
+    if status & PERIODIC_INTERRUPT != 0 {
+        PERIODIC_TICKS.fetch_add(1, Ordering::SeqCst);
+    }
+    if status & ALARM_INTERRUPT != 0 {
+        ALARM_FIRED.store(true, Ordering::SeqCst);
+        ALARM_WAKER.wake();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DateTime, SystemTime};
+    use crate::{serial_print, serial_println};
+    use core::time::Duration;
+    #[test_case]
+    fn unix_timestamp() {
+        serial_print!("rtc::unix_timestamp... ");
+        let date_time =
+            |year: u16, month: u8, day: u8, hour: u8, minute: u8, second: u8| DateTime {
+                year,
+                month,
+                day,
+                hour,
+                minute,
+                second,
+            };
+        assert_eq!(date_time(1970, 1, 1, 0, 0, 0).unix_timestamp(), 0);
+        assert_eq!(date_time(2000, 3, 1, 0, 0, 0).unix_timestamp(), 951_868_800);
+        assert_eq!(
+            date_time(2020, 2, 29, 12, 34, 56).unix_timestamp(),
+            1_582_979_696
+        );
+        serial_println!("[ok]");
+    }
+    #[test_case]
+    fn hour_modes() {
+        serial_print!("rtc::hour_modes... ");
+        // 12 hour BCD mode.
+        assert_eq!(super::decode_hour(0x12, 0), 0);
+        assert_eq!(super::decode_hour(0x92, 0), 12);
+        assert_eq!(super::decode_hour(0x81, 0), 13);
+        assert_eq!(super::encode_hour(0, 0), 0x12);
+        assert_eq!(super::encode_hour(23, 0), 0x91);
+        // 24 hour binary mode.
+        let mode = super::HOUR_24 | super::BINARY;
+        assert_eq!(super::decode_hour(23, mode), 23);
+        assert_eq!(super::encode_hour(23, mode), 23);
+        serial_println!("[ok]");
+    }
+    #[test_case]
+    fn system_time() {
+        serial_print!("rtc::system_time... ");
+        let now = SystemTime::now();
+        let since_epoch = now
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .expect("before the epoch");
+        // later than 2020-01-01.
+        assert!(since_epoch.as_secs() > 1_577_836_800);
+        assert!(SystemTime::UNIX_EPOCH.duration_since(now).is_err());
+        serial_println!("[ok]");
+    }
+    #[test_case]
+    fn periodic_interrupt() {
+        serial_print!("rtc::periodic_interrupt... ");
+        let start = super::periodic_ticks();
+        // 1024Hz.
+        super::enable_periodic(6);
+        let deadline = crate::time::uptime() + Duration::from_secs(1);
+        while super::periodic_ticks() < start + 10 {
+            assert!(crate::time::uptime() < deadline, "no periodic interrupt");
+            // the timer tick wakes it up, too.
+            x86_64::instructions::hlt();
+        }
+        super::disable_periodic();
+        serial_println!("[ok]");
+    }
+}
@@ -44,6 +44,7 @@ impl Executor {
     /// Run executor.
     pub fn run(&mut self) -> ! {
         loop {
+            super::timer::wake_expired();
             self.wake_tasks();
             self.poll_tasks();
             self.sleep_if_idle();
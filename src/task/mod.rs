@@ -11,9 +11,11 @@ use core::{
 mod executor;
 pub(crate) mod keyboard;
 mod simple;
+pub mod timer;
 
 /// Re-exports.
 pub use executor::Executor;
+pub use timer::{interval, sleep, timeout};
 
 /// Async task.
 pub struct Task {
@@ -0,0 +1,223 @@
+//! Async timers
+//!
+//! The sleeping tasks register their wakers under the deadline, and the
+//! timer interrupt wakes the expired ones on each tick.  The executor drops
+//! those on every loop, and wakes the ones the interrupt missed.
+use super::alloc::collections::BTreeMap;
+use core::{
+    fmt,
+    future::Future,
+    mem,
+    pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+use futures_util::stream::Stream;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+// deadline in nanoseconds since boot, and the timer ID for the same
+// deadlines.
+type TimerKey = (u64, u64);
+
+// the waker, and true once the timer interrupt woke it.
+type TimerEntry = (Waker, bool);
+
+static TIMERS: Mutex<Option<BTreeMap<TimerKey, TimerEntry>>> = Mutex::new(None);
+
+/// Wakes the tasks of the expired timers from the timer interrupt.
+///
+/// It leaves the entries in place, as the removal may free the map nodes,
+/// and gives up when the lock is taken, as `wake_expired` catches up.
+pub(crate) fn tick() {
+    let now = nanos(crate::time::uptime());
+    let mut timers = match TIMERS.try_lock() {
+        Some(timers) => timers,
+        None => return,
+    };
+    if let Some(timers) = timers.as_mut() {
+        for (_, (waker, woken)) in timers.iter_mut().take_while(|(key, _)| key.0 <= now) {
+            if !*woken {
+                waker.wake_by_ref();
+                *woken = true;
+            }
+        }
+    }
+}
+
+/// Drops the expired timers, and wakes the tasks the timer interrupt
+/// missed.
+pub(super) fn wake_expired() {
+    let now = nanos(crate::time::uptime());
+    let expired = interrupts::without_interrupts(|| {
+        let mut timers = TIMERS.lock();
+        let timers = timers.get_or_insert_with(BTreeMap::new);
+        let pending = timers.split_off(&(now + 1, 0));
+        mem::replace(timers, pending)
+    });
+    for (_, (waker, woken)) in expired {
+        if !woken {
+            waker.wake();
+        }
+    }
+}
+
+/// Returns the future which completes after `duration`.
+pub fn sleep(duration: Duration) -> Sleep {
+    sleep_until(crate::time::uptime() + duration)
+}
+
+/// Returns the future which completes once the uptime reaches `deadline`.
+pub fn sleep_until(deadline: Duration) -> Sleep {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+    Sleep {
+        key: (nanos(deadline), NEXT_ID.fetch_add(1, Ordering::Relaxed)),
+        registered: false,
+    }
+}
+
+/// Sleep future, returned by `sleep` and `sleep_until`.
+#[derive(Debug)]
+pub struct Sleep {
+    key: TimerKey,
+    registered: bool,
+}
+
+impl Sleep {
+    /// Returns the deadline as the uptime.
+    pub fn deadline(&self) -> Duration {
+        Duration::from_nanos(self.key.0)
+    }
+    fn is_elapsed(&self) -> bool {
+        nanos(crate::time::uptime()) >= self.key.0
+    }
+    fn unregister(&mut self) {
+        if self.registered {
+            interrupts::without_interrupts(|| {
+                if let Some(timers) = TIMERS.lock().as_mut() {
+                    timers.remove(&self.key);
+                }
+            });
+            self.registered = false;
+        }
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.is_elapsed() {
+            self.unregister();
+            return Poll::Ready(());
+        }
+        let waker = cx.waker().clone();
+        interrupts::without_interrupts(|| {
+            TIMERS
+                .lock()
+                .get_or_insert_with(BTreeMap::new)
+                .insert(self.key, (waker, false));
+        });
+        self.registered = true;
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        self.unregister();
+    }
+}
+
+/// Returns the stream which yields every `period`, starting after the first
+/// `period`.
+pub fn interval(period: Duration) -> Interval {
+    assert!(period > Duration::from_nanos(0), "zero interval period");
+    Interval {
+        period,
+        sleep: sleep(period),
+    }
+}
+
+/// Interval stream, returned by `interval`.
+///
+/// The missed ticks are skipped, instead of being yielded in a burst.
+#[derive(Debug)]
+pub struct Interval {
+    period: Duration,
+    sleep: Sleep,
+}
+
+impl Interval {
+    /// Waits for the next tick and returns its deadline.
+    pub async fn tick(&mut self) -> Duration {
+        (&mut self.sleep).await;
+        self.advance()
+    }
+    fn advance(&mut self) -> Duration {
+        let deadline = self.sleep.deadline();
+        let mut next = deadline + self.period;
+        let now = crate::time::uptime();
+        while next <= now {
+            next += self.period;
+        }
+        self.sleep = sleep_until(next);
+        deadline
+    }
+}
+
+impl Stream for Interval {
+    type Item = Duration;
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Duration>> {
+        match Pin::new(&mut self.sleep).poll(cx) {
+            Poll::Ready(()) => Poll::Ready(Some(self.advance())),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// Returns the future which completes with `future`'s output, or with
+/// `Elapsed` when it doesn't complete in `duration`.
+pub fn timeout<F: Future>(future: F, duration: Duration) -> Timeout<F> {
+    Timeout {
+        future,
+        sleep: sleep(duration),
+    }
+}
+
+/// Timeout future, returned by `timeout`.
+#[derive(Debug)]
+pub struct Timeout<F> {
+    future: F,
+    sleep: Sleep,
+}
+
+/// Timeout error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Elapsed;
+
+impl fmt::Display for Elapsed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "deadline has elapsed")
+    }
+}
+
+impl<F: Future> Future for Timeout<F> {
+    type Output = Result<F::Output, Elapsed>;
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // The future is never moved out of the pinned timeout.
+        let this = unsafe { self.get_unchecked_mut() };
+        let future = unsafe { Pin::new_unchecked(&mut this.future) };
+        if let Poll::Ready(output) = future.poll(cx) {
+            return Poll::Ready(Ok(output));
+        }
+        match Pin::new(&mut this.sleep).poll(cx) {
+            Poll::Ready(()) => Poll::Ready(Err(Elapsed)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+fn nanos(duration: Duration) -> u64 {
+    duration.as_nanos() as u64
+}
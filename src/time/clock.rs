@@ -0,0 +1,206 @@
+//! Clock sources and the high resolution `Instant`
+use super::tsc;
+use conquer_once::spin::OnceCell;
+use core::{
+    fmt,
+    ops::{Add, AddAssign, Sub, SubAssign},
+    time::Duration,
+};
+
+/// Monotonic clock source.
+pub trait ClockSource: Sync {
+    /// Returns the clock source name.
+    fn name(&self) -> &'static str;
+    /// Returns the time since boot in nanoseconds.
+    fn now_nanos(&self) -> u64;
+    /// Returns the clock resolution.
+    fn resolution(&self) -> Duration;
+}
+
+/// Invariant TSC clock source, with the nanosecond resolution.
+///
+/// It only comes out of the calibration, so the frequency is never zero.
+#[derive(Debug)]
+pub struct TscClock {
+    // calibrated frequency in Hz.
+    hz: u64,
+    // TSC value at the calibration.
+    base: u64,
+}
+
+impl TscClock {
+    // returns the clock source when the TSC is invariant and calibrates.
+    fn calibrate() -> Option<Self> {
+        if !tsc::is_invariant() {
+            return None;
+        }
+        let hz = tsc::calibrate().filter(|&hz| hz != 0)?;
+        // The TSC clock starts with the tick clock, at zero.
+        Some(Self {
+            hz,
+            base: tsc::read(),
+        })
+    }
+}
+
+impl ClockSource for TscClock {
+    fn name(&self) -> &'static str {
+        "tsc"
+    }
+    fn now_nanos(&self) -> u64 {
+        let cycles = u128::from(tsc::read().wrapping_sub(self.base));
+        (cycles * 1_000_000_000 / u128::from(self.hz)) as u64
+    }
+    fn resolution(&self) -> Duration {
+        Duration::from_nanos((1_000_000_000 + self.hz - 1) / self.hz)
+    }
+}
+
+/// Timer tick clock source, the fallback with the tick resolution.
+#[derive(Debug)]
+pub struct TickClock;
+
+impl ClockSource for TickClock {
+    fn name(&self) -> &'static str {
+        "tick"
+    }
+    fn now_nanos(&self) -> u64 {
+        super::uptime().as_nanos() as u64
+    }
+    fn resolution(&self) -> Duration {
+        Duration::from_nanos(1_000_000_000 / u64::from(super::frequency().max(1)))
+    }
+}
+
+static TSC_CLOCK: OnceCell<TscClock> = OnceCell::uninit();
+static TICK_CLOCK: TickClock = TickClock;
+
+/// Picks the TSC when it's invariant and calibrates, or the timer tick
+/// otherwise.
+pub(super) fn init() {
+    if let Some(clock) = TscClock::calibrate() {
+        TSC_CLOCK
+            .try_init_once(|| clock)
+            .expect("clock should only be initialized once");
+    }
+    let clock = clock_source();
+    crate::serial_println!(
+        "time: {} clock source, {:?} resolution",
+        clock.name(),
+        clock.resolution(),
+    );
+}
+
+/// Returns the active clock source.
+pub fn clock_source() -> &'static dyn ClockSource {
+    match TSC_CLOCK.try_get() {
+        Ok(clock) => clock,
+        Err(_) => &TICK_CLOCK,
+    }
+}
+
+/// Returns the calibrated TSC frequency in Hz, when the TSC is the clock
+/// source.
+pub fn tsc_frequency() -> Option<u64> {
+    TSC_CLOCK.try_get().ok().map(|clock| clock.hz)
+}
+
+/// Monotonic timestamp of the active clock source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(u64);
+
+impl Instant {
+    /// Returns the current instant.
+    pub fn now() -> Self {
+        Self(clock_source().now_nanos())
+    }
+    /// Returns the time elapsed since the instant.
+    pub fn elapsed(&self) -> Duration {
+        Self::now().saturating_duration_since(*self)
+    }
+    /// Returns the time from `earlier` to the instant, or `None` when
+    /// `earlier` is later.
+    pub fn checked_duration_since(&self, earlier: Instant) -> Option<Duration> {
+        self.0.checked_sub(earlier.0).map(Duration::from_nanos)
+    }
+    /// Returns the time from `earlier` to the instant, or zero when
+    /// `earlier` is later.
+    pub fn saturating_duration_since(&self, earlier: Instant) -> Duration {
+        Duration::from_nanos(self.0.saturating_sub(earlier.0))
+    }
+    /// Returns the time from `earlier` to the instant.
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        self.checked_duration_since(earlier)
+            .expect("earlier instant is later")
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+    fn add(self, rhs: Duration) -> Instant {
+        Instant(self.0 + rhs.as_nanos() as u64)
+    }
+}
+
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, rhs: Duration) {
+        *self = *self + rhs;
+    }
+}
+
+impl Sub<Duration> for Instant {
+    type Output = Instant;
+    fn sub(self, rhs: Duration) -> Instant {
+        Instant(self.0 - rhs.as_nanos() as u64)
+    }
+}
+
+impl SubAssign<Duration> for Instant {
+    fn sub_assign(&mut self, rhs: Duration) {
+        *self = *self - rhs;
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+    fn sub(self, rhs: Instant) -> Duration {
+        self.duration_since(rhs)
+    }
+}
+
+impl fmt::Display for Instant {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}.{:09}",
+            self.0 / 1_000_000_000,
+            self.0 % 1_000_000_000
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Instant;
+    use crate::{serial_print, serial_println};
+    use core::time::Duration;
+    #[test_case]
+    fn instant_advances() {
+        serial_print!("time::clock::instant_advances... ");
+        let start = Instant::now();
+        let resolution = super::clock_source().resolution();
+        while start.elapsed() < resolution * 2 {
+            core::sync::atomic::spin_loop_hint();
+        }
+        let later = Instant::now();
+        assert!(later > start);
+        assert!(later - start >= resolution);
+        assert_eq!(start + (later - start), later);
+        assert_eq!(start.checked_duration_since(later), None);
+        assert_eq!(
+            start.saturating_duration_since(later),
+            Duration::from_nanos(0)
+        );
+        serial_println!("[ok]");
+    }
+}
@@ -0,0 +1,92 @@
+//! Programmable interval timer, the monotonic uptime and the clock sources
+//!
+//! The PIT channel 0 fires the timer interrupt at `frequency()` Hz, and
+//! each tick adds the tick period to the uptime, so that the frequency can
+//! be changed at any time without the uptime jumping.  The invariant TSC,
+//! when available, provides the nanosecond resolution `Instant`.
+mod clock;
+pub mod tsc;
+
+use core::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+use x86_64::instructions::{interrupts, port::Port};
+
+// re-exports.
+pub use clock::{clock_source, tsc_frequency, ClockSource, Instant, TickClock, TscClock};
+
+/// Default timer interrupt frequency in Hz.
+pub const DEFAULT_FREQUENCY: u32 = 100;
+
+/// PIT input clock frequency in Hz.
+pub(crate) const PIT_FREQUENCY: u32 = 1_193_182;
+
+const PIT_CHANNEL_0: u16 = 0x40;
+const PIT_COMMAND: u16 = 0x43;
+// channel 0, lobyte/hibyte access, mode 2 (rate generator), binary.
+const PIT_RATE_GENERATOR: u8 = 0b0011_0100;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+static UPTIME_NANOS: AtomicU64 = AtomicU64::new(0);
+static PERIOD_NANOS: AtomicU64 = AtomicU64::new(0);
+
+pub(crate) fn init() {
+    set_frequency(DEFAULT_FREQUENCY);
+    clock::init();
+}
+
+/// Programs the timer interrupt to fire at `hz`, which is rounded to the
+/// nearest frequency the PIT supports, from 19Hz to the PIT frequency.
+pub fn set_frequency(hz: u32) {
+    assert!(hz > 0, "zero timer frequency");
+    let divisor = ((PIT_FREQUENCY + hz / 2) / hz).max(1).min(0xffff);
+    let period = u64::from(divisor) * 1_000_000_000 / u64::from(PIT_FREQUENCY);
+    let mut command: Port<u8> = Port::new(PIT_COMMAND);
+    let mut data: Port<u8> = Port::new(PIT_CHANNEL_0);
+    interrupts::without_interrupts(|| unsafe {
+        command.write(PIT_RATE_GENERATOR);
+        data.write(divisor as u8);
+        data.write((divisor >> 8) as u8);
+        PERIOD_NANOS.store(period, Ordering::SeqCst);
+    });
+}
+
+/// Returns the programmed timer interrupt frequency in Hz.
+pub fn frequency() -> u32 {
+    let period = PERIOD_NANOS.load(Ordering::SeqCst);
+    if period == 0 {
+        return 0;
+    }
+    ((1_000_000_000 + period / 2) / period) as u32
+}
+
+/// Returns the number of the timer interrupts since boot.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::SeqCst)
+}
+
+/// Returns the time since the timer initialization, with the timer tick
+/// resolution.
+pub fn uptime() -> Duration {
+    Duration::from_nanos(UPTIME_NANOS.load(Ordering::SeqCst))
+}
+
+/// Accounts one timer tick, called by the timer interrupt handler.
+pub(crate) fn tick() {
+    TICKS.fetch_add(1, Ordering::SeqCst);
+    let period = PERIOD_NANOS.load(Ordering::SeqCst);
+    UPTIME_NANOS.fetch_add(period, Ordering::SeqCst);
+    crate::task::timer::tick();
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{serial_print, serial_println};
+    #[test_case]
+    fn frequency() {
+        serial_print!("time::frequency... ");
+        assert_eq!(super::frequency(), super::DEFAULT_FREQUENCY);
+        serial_println!("[ok]");
+    }
+}
@@ -0,0 +1,80 @@
+//! Time stamp counter calibration
+//!
+//! The TSC frequency is measured against the PIT channel 2 one-shot, which
+//! is polled through the speaker port, so that it works before the
+//! interrupts are enabled.
+use super::PIT_FREQUENCY;
+use core::arch::x86_64::{__cpuid, _rdtsc};
+use x86_64::instructions::{interrupts, port::Port};
+
+const PIT_CHANNEL_2: u16 = 0x42;
+const PIT_COMMAND: u16 = 0x43;
+const SPEAKER_PORT: u16 = 0x61;
+// channel 2, lobyte/hibyte access, mode 0 (interrupt on terminal count),
+// binary.
+const PIT_ONE_SHOT: u8 = 0b1011_0000;
+const SPEAKER_GATE: u8 = 1 << 0;
+const SPEAKER_DATA: u8 = 1 << 1;
+const PIT_OUTPUT: u8 = 1 << 5;
+
+// 10ms calibration window, repeated to take the least disturbed one.
+const CALIBRATION_COUNT: u16 = (PIT_FREQUENCY / 100) as u16;
+const CALIBRATION_RUNS: usize = 3;
+const CALIBRATION_SPIN_LIMIT: u64 = 100_000_000;
+
+/// Returns true when the TSC runs at the constant rate in all the ACPI P-,
+/// C- and T-states.
+pub fn is_invariant() -> bool {
+    let max_extended_leaf = unsafe { __cpuid(0x8000_0000) }.eax;
+    if max_extended_leaf < 0x8000_0007 {
+        return false;
+    }
+    // CPUID.80000007H:EDX.InvariantTSC[bit 8]
+    unsafe { __cpuid(0x8000_0007) }.edx & (1 << 8) != 0
+}
+
+/// Reads the time stamp counter.
+pub fn read() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+/// Returns the TSC frequency in Hz, or `None` when the PIT channel 2 is
+/// not there to measure it.
+///
+/// The failed runs are skipped, and the shortest of the rest wins.
+pub(super) fn calibrate() -> Option<u64> {
+    let cycles = (0..CALIBRATION_RUNS)
+        .filter_map(|_| interrupts::without_interrupts(measure))
+        .min()?;
+    if cycles == 0 {
+        return None;
+    }
+    Some(cycles * u64::from(PIT_FREQUENCY) / u64::from(CALIBRATION_COUNT))
+}
+
+// returns the TSC cycles of the one-shot PIT channel 2 countdown.
+fn measure() -> Option<u64> {
+    let mut speaker: Port<u8> = Port::new(SPEAKER_PORT);
+    let mut command: Port<u8> = Port::new(PIT_COMMAND);
+    let mut data: Port<u8> = Port::new(PIT_CHANNEL_2);
+    unsafe {
+        // gate on, speaker off.
+        let value = speaker.read();
+        speaker.write((value & !SPEAKER_DATA) | SPEAKER_GATE);
+        command.write(PIT_ONE_SHOT);
+        data.write(CALIBRATION_COUNT as u8);
+        data.write((CALIBRATION_COUNT >> 8) as u8);
+        let start = read();
+        let mut spins = 0;
+        while speaker.read() & PIT_OUTPUT == 0 {
+            spins += 1;
+            if spins > CALIBRATION_SPIN_LIMIT {
+                speaker.write(value);
+                return None;
+            }
+        }
+        let end = read();
+        speaker.write(value);
+        Some(end - start)
+    }
+}
@@ -0,0 +1,50 @@
+//! Double free detection by the `alloc-debug` allocator
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(test_runner)]
+#![reexport_test_harness_main = "test_main"]
+extern crate alloc;
+extern crate rustos;
+use alloc::boxed::Box;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rustos::{exit_qemu, serial_print, serial_println, QemuExitCode};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rustos::memory::init(boot_info);
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    loop {}
+}
+
+pub fn test_runner(tests: &[&dyn Fn()]) {
+    serial_println!("Running {} tests", tests.len());
+    for test in tests {
+        test();
+        serial_println!("[test did not panic]");
+        exit_qemu(QemuExitCode::Failed);
+    }
+    exit_qemu(QemuExitCode::Success);
+}
+
+#[test_case]
+fn double_free() {
+    serial_print!("tests::double_free::double_free... ");
+    let ptr = Box::into_raw(Box::new(42u64));
+    unsafe {
+        drop(Box::from_raw(ptr));
+        // the freed block is not handed out again right away.
+        let other = Box::new(7u64);
+        assert_ne!(&*other as *const u64, ptr as *const u64);
+        drop(Box::from_raw(ptr));
+    }
+}
@@ -55,6 +55,8 @@ fn many_boxes() {
     serial_println!("[ok]");
 }
 
+// The bump allocator can't reuse the memory while the long lived box is alive.
+#[cfg(not(feature = "alloc-bump"))]
 #[test_case]
 fn many_boxes_long_lived() {
     use alloc::boxed::Box;
@@ -67,3 +69,96 @@ fn many_boxes_long_lived() {
     assert_eq!(*long_lived, 1);
     serial_println!("[ok]");
 }
+
+#[test_case]
+fn stats() {
+    use alloc::boxed::Box;
+    serial_print!("tests::heap_allocation::stats... ");
+    let before = rustos::allocator::stats();
+    let x = Box::new([1u64; 8]);
+    let during = rustos::allocator::stats();
+    assert_eq!(during.allocated, before.allocated + 64);
+    assert_eq!(during.allocations, before.allocations + 1);
+    assert!(during.peak >= during.allocated);
+    assert!(during.free < before.free);
+    assert!(during.free + during.allocated <= during.heap_size);
+    drop(x);
+    let after = rustos::allocator::stats();
+    assert_eq!(after.allocated, before.allocated);
+    assert_eq!(after.allocations, before.allocations);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn fallible_allocation() {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    use rustos::allocator::{self, AllocError};
+    static RECLAIMED: AtomicUsize = AtomicUsize::new(0);
+    fn oom_handler() -> usize {
+        RECLAIMED.fetch_add(1, Ordering::Relaxed);
+        0
+    }
+    serial_print!("tests::heap_allocation::fallible_allocation... ");
+    allocator::register_oom_handler(oom_handler).expect("no room for OOM handler");
+    let x = allocator::try_box(41).expect("try_box failed");
+    assert_eq!(*x, 41);
+    let v = allocator::try_vec_with_capacity::<u64>(100).expect("try_vec failed");
+    assert!(v.capacity() >= 100);
+    let layout = core::alloc::Layout::from_size_align(0, 16).unwrap();
+    let ptr = allocator::try_alloc(layout).expect("zero sized try_alloc failed");
+    assert_eq!(ptr.as_ptr() as usize % 16, 0);
+    let err = allocator::try_vec_with_capacity::<u8>(usize::max_value()).unwrap_err();
+    assert_eq!(err, AllocError::CapacityOverflow);
+    // the request beyond the ceiling doesn't grow the heap at all.
+    let heap_size = allocator::stats().heap_size;
+    let err = allocator::try_vec_with_capacity::<u8>(rustos::HEAP_MAX_SIZE * 2).unwrap_err();
+    assert!(matches!(err, AllocError::OutOfMemory(_)));
+    assert_eq!(allocator::stats().heap_size, heap_size);
+    assert!(RECLAIMED.load(Ordering::Relaxed) > 0);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn interleaved_allocations() {
+    use alloc::{boxed::Box, vec, vec::Vec};
+    serial_print!("tests::heap_allocation::interleaved_allocations... ");
+    let n = 64;
+    let mut boxes: Vec<Option<Box<[u8]>>> = Vec::with_capacity(n);
+    for i in 0..n {
+        boxes.push(Some(vec![i as u8; (i % 7 + 1) * 64].into_boxed_slice()));
+    }
+    // free every other allocation and fill the holes with the smaller ones.
+    for i in (0..n).step_by(2) {
+        boxes[i] = None;
+    }
+    for i in (0..n).step_by(2) {
+        boxes[i] = Some(vec![i as u8; (i % 3 + 1) * 16].into_boxed_slice());
+    }
+    for (i, b) in boxes.iter().enumerate() {
+        assert!(b.as_ref().unwrap().iter().all(|&x| x == i as u8));
+    }
+    // free in the interleaved order.
+    for i in (1..n).step_by(2).chain((0..n).step_by(2)) {
+        boxes[i] = None;
+    }
+    drop(boxes);
+    let big = vec![1u8; rustos::HEAP_SIZE - 4096];
+    assert_eq!(big.iter().map(|&x| x as usize).sum::<usize>(), big.len());
+    serial_println!("[ok]");
+}
+
+// Only the block allocator grows the heap.
+#[cfg(not(any(feature = "alloc-bump", feature = "alloc-list")))]
+#[test_case]
+fn beyond_heap_size() {
+    use alloc::vec::Vec;
+    serial_print!("tests::heap_allocation::beyond_heap_size... ");
+    let n = rustos::HEAP_SIZE * 2;
+    let mut vec = Vec::with_capacity(n);
+    for i in 0..n {
+        vec.push(i as u8);
+    }
+    assert_eq!(vec.len(), n);
+    assert_eq!(vec[n - 1], (n - 1) as u8);
+    serial_println!("[ok]");
+}
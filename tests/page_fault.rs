@@ -7,11 +7,18 @@
 #![test_runner(rustos::test_runner)]
 #![reexport_test_harness_main = "test_main"]
 extern crate rustos;
+use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo;
+use rustos::memory::{self, vmm, RegionKind};
 use rustos::{serial_print, serial_println};
+use x86_64::structures::paging::PageTableFlags;
 
-#[no_mangle]
-pub extern "C" fn _start() -> ! {
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    // setup the interrupt descriptor table to catch the page fault.
+    rustos::init();
+    memory::init(boot_info);
     test_main();
     loop {}
 }
@@ -24,8 +31,6 @@ fn panic(info: &PanicInfo) -> ! {
 #[test_case]
 fn page_fault() {
     serial_print!("tests::page_fault::page_fault... ");
-    // setup the interrupt descriptor table to catch the page fault.
-    rustos::init();
     /* This code will be enabled with the correct page address
        once we fixes the page fault handler.
     unsafe {
@@ -34,3 +39,19 @@ fn page_fault() {
     */
     serial_println!("[ok]");
 }
+
+#[test_case]
+fn lazy_region() {
+    serial_print!("tests::page_fault::lazy_region... ");
+    let flags = PageTableFlags::WRITABLE;
+    let region = vmm::reserve_lazy("lazy", RegionKind::Anonymous, 4, flags).expect("no region");
+    let free = memory::free_frames();
+    let ptr = (region.start + 4096u64 + 8u64).as_mut_ptr::<u64>();
+    // the first touch is backed by a zeroed frame.
+    assert_eq!(unsafe { ptr.read_volatile() }, 0);
+    unsafe { ptr.write_volatile(42) };
+    assert_eq!(unsafe { ptr.read_volatile() }, 42);
+    assert!(memory::free_frames() < free);
+    unsafe { vmm::release(region.start).expect("release failed") };
+    serial_println!("[ok]");
+}
@@ -8,15 +8,16 @@ extern crate rustos;
 extern crate x86_64;
 use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo;
+use rustos::memory::{self, vmm, RegionKind};
 use rustos::{serial_print, serial_println};
-use x86_64::{structures::paging::MapperAllSizes, PhysAddr, VirtAddr};
+use x86_64::{structures::paging::PageTableFlags, PhysAddr, VirtAddr};
 
 entry_point!(test_kernel);
 
 fn test_kernel(boot_info: &'static BootInfo) -> ! {
     serial_print!("tests::page_table::test_kernel... ");
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
-    let mapper = unsafe { rustos::memory::init_page_table(phys_mem_offset) };
+    memory::init(boot_info);
     let addresses = [
         // the identity-mapped vga buffer page.
         0xb8000,
@@ -24,14 +25,33 @@ fn test_kernel(boot_info: &'static BootInfo) -> ! {
         0x0020_1008,
         // some stack page.
         0x0100_0020_1a10,
+        // the vga buffer in the physical memory window, mapped with the
+        // huge pages.
+        phys_mem_offset.as_u64() + 0xb8000,
     ];
     for &address in &addresses {
         let virt = VirtAddr::new(address);
         let want = unsafe { translate_addr(virt, phys_mem_offset) };
-        let got = mapper.translate_addr(virt);
+        let got = memory::translate_addr(virt);
         assert_eq!(got, want);
     }
     serial_println!("[ok]");
+    serial_print!("tests::page_table::huge_page... ");
+    let flags = PageTableFlags::WRITABLE;
+    let region = vmm::allocate_huge("huge", RegionKind::Anonymous, 512, flags).expect("no region");
+    for &offset in &[0, 0x1238, 0x1f_fff8] {
+        let virt = region.start + offset;
+        unsafe { virt.as_mut_ptr::<u64>().write_volatile(offset) };
+        let want = unsafe { translate_addr(virt, phys_mem_offset) };
+        let got = memory::translate_addr(virt);
+        assert!(got.is_some());
+        assert_eq!(got, want);
+        let alias = phys_mem_offset + got.unwrap().as_u64();
+        assert_eq!(unsafe { alias.as_ptr::<u64>().read_volatile() }, offset);
+    }
+    unsafe { vmm::release(region.start).expect("release failed") };
+    assert!(memory::translate_addr(region.start).is_none());
+    serial_println!("[ok]");
     test_main();
     rustos::hlt_loop()
 }
@@ -56,8 +76,10 @@ fn translate_addr_inner(addr: VirtAddr, physical_memory_offset: VirtAddr) -> Opt
         addr.p2_index(),
         addr.p1_index(),
     ];
+    // the page sizes mapped by the P3 and the P2 entries.
+    let huge_page_sizes = [0, 1 << 30, 1 << 21, 0];
     let mut frame = level_4_table_frame;
-    for &index in &table_indexes {
+    for (level, &index) in table_indexes.iter().enumerate() {
         let virt = physical_memory_offset + frame.start_address().as_u64();
         let table_ptr: *const PageTable = virt.as_ptr();
         let table = unsafe { &*table_ptr };
@@ -65,7 +87,10 @@ fn translate_addr_inner(addr: VirtAddr, physical_memory_offset: VirtAddr) -> Opt
         frame = match entry.frame() {
             Ok(frame) => frame,
             Err(FrameError::FrameNotPresent) => return None,
-            Err(FrameError::HugeFrame) => panic!("huge pages not supported"),
+            Err(FrameError::HugeFrame) => {
+                let offset = addr.as_u64() & (huge_page_sizes[level] - 1);
+                return Some(entry.addr() + offset);
+            }
         };
     }
     Some(frame.start_address() + u64::from(addr.page_offset()))
@@ -0,0 +1,106 @@
+//! Supervisor mode access prevention
+#![no_std]
+#![no_main]
+#![feature(abi_x86_interrupt)]
+#![feature(custom_test_frameworks)]
+#![test_runner(test_runner)]
+#![reexport_test_harness_main = "test_main"]
+extern crate rustos;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use lazy_static::lazy_static;
+use rustos::memory::{self, space::USER_START, AddressSpace};
+use rustos::{exit_qemu, serial_print, serial_println, QemuExitCode};
+use x86_64::{
+    structures::{
+        idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode},
+        paging::{Page, PageTableFlags},
+    },
+    VirtAddr,
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rustos::init();
+    // no timer interrupt handler in the test IDT.
+    x86_64::instructions::interrupts::disable();
+    memory::init(boot_info);
+    TEST_IDT.load();
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rustos::test_panic_handler(info);
+}
+
+pub fn test_runner(tests: &[&dyn Fn()]) {
+    serial_println!("Running {} tests", tests.len());
+    for test in tests {
+        test();
+        serial_println!("[test did not fault]");
+        exit_qemu(QemuExitCode::Failed);
+    }
+    exit_qemu(QemuExitCode::Success);
+}
+
+lazy_static! {
+    static ref TEST_IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        idt.page_fault.set_handler_fn(test_page_fault_handler);
+        idt
+    };
+}
+
+extern "x86-interrupt" fn test_page_fault_handler(
+    _stack_frame: &mut InterruptStackFrame,
+    error_code: PageFaultErrorCode,
+) {
+    // SMAP violation, which is the kernel access to the present page.
+    let unwanted = PageFaultErrorCode::USER_MODE | PageFaultErrorCode::INSTRUCTION_FETCH;
+    if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION)
+        && !error_code.intersects(unwanted)
+    {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]\n");
+        serial_println!("Error: unexpected page fault: {:?}", error_code);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    loop {}
+}
+
+#[test_case]
+fn user_access() {
+    serial_print!("tests::smap::user_access... ");
+    // run by `make test-smap` on the CPU with SMEP and SMAP, which
+    // requires those.
+    if cfg!(feature = "require-smap") {
+        assert!(memory::has_smep(), "no SMEP support");
+        assert!(memory::has_smap(), "no SMAP support");
+    } else if !memory::has_smep() || !memory::has_smap() {
+        serial_println!("[skipped: no SMEP or SMAP support]");
+        exit_qemu(QemuExitCode::Success);
+    }
+    let addr = VirtAddr::new(USER_START);
+    let mut space = AddressSpace::new().expect("no address space");
+    space
+        .map_user(
+            Page::containing_address(addr),
+            PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+        )
+        .expect("map failed");
+    let ptr = addr.as_mut_ptr::<u8>();
+    let mut buf = [0u8; 4];
+    unsafe {
+        space.activate();
+        memory::user_copy_to(ptr, b"user");
+        memory::user_copy_from(&mut buf, ptr);
+    }
+    assert_eq!(&buf, b"user");
+    // the unguarded access faults.
+    unsafe { ptr.read_volatile() };
+}
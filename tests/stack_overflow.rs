@@ -7,20 +7,53 @@
 #![test_runner(test_runner)]
 #![reexport_test_harness_main = "test_main"]
 extern crate rustos;
-use core::panic::PanicInfo;
+use bootloader::{entry_point, BootInfo};
+use core::{fmt::Write, panic::PanicInfo};
 use rustos::{exit_qemu, serial_print, serial_println, QemuExitCode};
 
-#[no_mangle]
-pub extern "C" fn _start() -> ! {
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    // the memory manager registers the boot stack guard page.
     rustos::init();
+    rustos::memory::init(boot_info);
     test_main();
     loop {}
 }
 
+/// Panic message buffer, as the double fault handler reports the guard page
+/// through the panic.
+struct Message {
+    buf: [u8; 256],
+    len: usize,
+}
+
+impl Write for Message {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let n = s.len().min(self.buf.len() - self.len);
+        self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
 #[panic_handler]
-fn panic(_info: &PanicInfo) -> ! {
-    serial_println!("[ok]");
-    exit_qemu(QemuExitCode::Success);
+fn panic(info: &PanicInfo) -> ! {
+    let mut message = Message {
+        buf: [0; 256],
+        len: 0,
+    };
+    let _ = write!(message, "{}", info);
+    let expected = b"stack overflow in kernel stack";
+    let message = &message.buf[..message.len];
+    if message.windows(expected.len()).any(|w| w == expected) {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]");
+        serial_println!("{}", info);
+        exit_qemu(QemuExitCode::Failed);
+    }
     loop {}
 }
 
@@ -37,11 +70,12 @@ pub fn test_runner(tests: &[&dyn Fn()]) {
 #[test_case]
 fn stack_overflow() {
     serial_print!("tests::stack_overflow::stack_overflow... ");
-    rustos::init();
     stack_overflow();
 }
 
 #[allow(unconditional_recursion)]
 fn stack_overflow() {
     stack_overflow();
+    // prevent the tail call optimization.
+    volatile::Volatile::new(0).read();
 }
@@ -0,0 +1,55 @@
+//! Async timers on the executor
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rustos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+extern crate rustos;
+use bootloader::{entry_point, BootInfo};
+use core::{panic::PanicInfo, time::Duration};
+use futures_util::{future, stream::StreamExt};
+use rustos::task::{self, timer, Executor, Task};
+use rustos::{memory, serial_print, serial_println, time, QemuExitCode};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rustos::init();
+    memory::init(boot_info);
+    rustos::init_apic();
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rustos::test_panic_handler(info);
+}
+
+#[test_case]
+fn timers() {
+    serial_print!("tests::timer::timers... ");
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(sleeper()));
+    executor.run();
+}
+
+async fn sleeper() {
+    let start = time::uptime();
+    timer::sleep(Duration::from_millis(30)).await;
+    assert!(time::uptime() >= start + Duration::from_millis(30));
+
+    let mut interval = timer::interval(Duration::from_millis(10));
+    let first = interval.next().await.expect("no tick");
+    let second = interval.tick().await;
+    assert!(second >= first + Duration::from_millis(10));
+
+    let fast = timer::timeout(async { 42 }, Duration::from_millis(50)).await;
+    assert_eq!(fast, Ok(42));
+    let never = future::pending::<()>();
+    let slow = timer::timeout(never, Duration::from_millis(10)).await;
+    assert_eq!(slow, Err(task::timer::Elapsed));
+
+    serial_println!("[ok]");
+    rustos::exit_qemu(QemuExitCode::Success);
+}
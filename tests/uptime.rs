@@ -0,0 +1,40 @@
+//! Timer tick based uptime
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rustos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+extern crate rustos;
+use bootloader::{entry_point, BootInfo};
+use core::{panic::PanicInfo, time::Duration};
+use rustos::{memory, serial_print, serial_println, time};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rustos::init();
+    memory::init(boot_info);
+    rustos::init_apic();
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rustos::test_panic_handler(info);
+}
+
+#[test_case]
+fn uptime_advances() {
+    serial_print!("tests::uptime::uptime_advances... ");
+    let start = time::uptime();
+    let ticks = time::ticks();
+    let mut spins = 0u64;
+    while time::uptime() < start + Duration::from_millis(50) {
+        spins += 1;
+        assert!(spins < 1_000_000_000, "uptime stuck at {:?}", start);
+        core::sync::atomic::spin_loop_hint();
+    }
+    assert!(time::ticks() >= ticks + 5);
+    serial_println!("[ok]");
+}
@@ -0,0 +1,68 @@
+//! Write protection of the kernel code
+#![no_std]
+#![no_main]
+#![feature(abi_x86_interrupt)]
+#![feature(custom_test_frameworks)]
+#![test_runner(test_runner)]
+#![reexport_test_harness_main = "test_main"]
+extern crate rustos;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use lazy_static::lazy_static;
+use rustos::{exit_qemu, serial_print, serial_println, QemuExitCode};
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rustos::memory::init(boot_info);
+    TEST_IDT.load();
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rustos::test_panic_handler(info);
+}
+
+pub fn test_runner(tests: &[&dyn Fn()]) {
+    serial_println!("Running {} tests", tests.len());
+    for test in tests {
+        test();
+        serial_println!("[test did not fault]");
+        exit_qemu(QemuExitCode::Failed);
+    }
+    exit_qemu(QemuExitCode::Success);
+}
+
+lazy_static! {
+    static ref TEST_IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        idt.page_fault.set_handler_fn(test_page_fault_handler);
+        idt
+    };
+}
+
+extern "x86-interrupt" fn test_page_fault_handler(
+    _stack_frame: &mut InterruptStackFrame,
+    error_code: PageFaultErrorCode,
+) {
+    let want = PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE;
+    if error_code.contains(want) {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]\n");
+        serial_println!("Error: unexpected page fault: {:?}", error_code);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    loop {}
+}
+
+#[test_case]
+fn write_to_code() {
+    serial_print!("tests::write_protect::write_to_code... ");
+    let code = main as *const () as *mut u8;
+    unsafe { code.write_volatile(0xcc) };
+}